@@ -1,7 +1,9 @@
 use futures::future;
+use program::client::simulate_balance;
 use serde::Deserialize;
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::pubkey::Pubkey;
+use std::error::Error;
 use std::fs;
 use std::str::FromStr;
 use tokio::task;
@@ -9,6 +11,12 @@ use tokio::task;
 #[derive(Debug, Deserialize)]
 struct Config {
     wallets: Vec<String>,
+    /// Bank program owning `ledgers`
+    program_id: Option<String>,
+    /// Funded account paying for the simulated `CheckBalance` transactions
+    fee_payer: Option<String>,
+    #[serde(default)]
+    ledgers: Vec<String>,
 }
 
 async fn get_balance(wallet_address: &str) -> (String, u64) {
@@ -47,4 +55,33 @@ async fn main() {
             }
         }
     }
+    // Ledger balances of the bank program
+    if !config.ledgers.is_empty() {
+        let program_id = Pubkey::from_str(
+            config
+                .program_id
+                .as_deref()
+                .expect("program_id is required for ledgers"),
+        )
+        .expect("Invalid program_id");
+        let fee_payer = Pubkey::from_str(
+            config
+                .fee_payer
+                .as_deref()
+                .expect("fee_payer is required for ledgers"),
+        )
+        .expect("Invalid fee_payer");
+        let client = RpcClient::new("https://api.devnet.solana.com");
+        for ledger in config.ledgers {
+            let balance = Pubkey::from_str(&ledger)
+                .map_err(Box::<dyn Error>::from)
+                .and_then(|pubkey| {
+                    Ok(simulate_balance(&client, &program_id, &pubkey, &fee_payer)?)
+                });
+            match balance {
+                Ok(balance) => println!("Ledger: {}, Balance: {} lamports", ledger, balance),
+                Err(e) => eprintln!("Error fetching ledger balance: {:?}", e),
+            }
+        }
+    }
 }
@@ -0,0 +1,78 @@
+//! Helpers for off-chain clients talking to the program over RPC.
+
+use crate::instruction;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use solana_client::{
+    client_error::ClientError, rpc_client::RpcClient, rpc_config::RpcSimulateTransactionConfig,
+};
+use solana_sdk::{
+    message::Message, pubkey::Pubkey, transaction::Transaction, transaction::TransactionError,
+};
+use std::fmt;
+
+/// Errors of reading a ledger balance with `simulate_balance`.
+#[derive(Debug)]
+pub enum BalanceError {
+    /// The RPC request failed
+    Rpc(Box<ClientError>),
+    /// The simulated `CheckBalance` transaction failed
+    Transaction(TransactionError),
+    /// The return data was not set by the program or does not hold a balance
+    InvalidReturnData,
+}
+
+impl fmt::Display for BalanceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BalanceError::Rpc(err) => write!(f, "RPC request failed: {}", err),
+            BalanceError::Transaction(err) => write!(f, "CheckBalance failed: {}", err),
+            BalanceError::InvalidReturnData => f.write_str("invalid balance return data"),
+        }
+    }
+}
+
+impl std::error::Error for BalanceError {}
+
+impl From<ClientError> for BalanceError {
+    fn from(err: ClientError) -> Self {
+        BalanceError::Rpc(Box::new(err))
+    }
+}
+
+/// Read the balance of `ledger` by simulating a `check_balance` instruction, so nothing
+/// is sent or signed. `fee_payer` only has to exist, it pays for nothing.
+pub fn simulate_balance(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    fee_payer: &Pubkey,
+) -> Result<u64, BalanceError> {
+    let message = Message::new(
+        &[instruction::check_balance(program_id, ledger)],
+        Some(fee_payer),
+    );
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: false,
+        replace_recent_blockhash: true,
+        ..RpcSimulateTransactionConfig::default()
+    };
+    let result = client
+        .simulate_transaction_with_config(&Transaction::new_unsigned(message), config)?
+        .value;
+    if let Some(err) = result.err {
+        return Err(BalanceError::Transaction(err));
+    }
+
+    let data = match result.return_data {
+        Some(return_data) => {
+            if return_data.program_id != program_id.to_string() {
+                return Err(BalanceError::InvalidReturnData);
+            }
+            STANDARD
+                .decode(return_data.data.0)
+                .map_err(|_| BalanceError::InvalidReturnData)?
+        }
+        None => vec![],
+    };
+    instruction::unpack_balance(&data).ok_or(BalanceError::InvalidReturnData)
+}
@@ -0,0 +1,1185 @@
+//! Wrappers for other programs calling this program through cross-program invocation.
+//!
+//! Each command has a function calling `invoke`, for accounts that signed the
+//! transaction of the caller, and a `_signed` variant calling `invoke_signed` with the
+//! seeds of the program-derived addresses signing for the caller. The account order
+//! is the one of the builders in [`crate::instruction`], and `program` is the account
+//! of this program. The instructions are built from the keys of the accounts passed in,
+//! without deriving the addresses of the ledger, vaults, config or fee vault again.
+//!
+//! A program-derived address of the caller can be the authority of a ledger or the
+//! admin of the config, whatever its owner. Accounts that pay lamports, the `user` of
+//! `initialize` and every `depositor` and `payer`, must in addition be owned by the
+//! system program and hold no data, as the system program transfers from them.
+//!
+//! Ledgers governed by a multisig are driven with [`crate::instruction::with_multisig_signers`]
+//! and `invoke` instead, passing the members as extra accounts.
+//!
+//! Depend on the crate with the `no-entrypoint` feature to use this module on chain.
+
+use crate::{
+    instruction::{self, command_instruction, unpack_balance},
+    Command, EscrowTerms, VestingSchedule, WithdrawalLimit,
+};
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::AccountMeta,
+    program::{get_return_data, invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+/// Create the ledger account of `user` and its vault, paid for by `user`.
+pub fn initialize<'a>(
+    program: &AccountInfo<'a>,
+    user: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> ProgramResult {
+    initialize_signed(program, user, ledger, vault, system_program, &[])
+}
+
+/// [`initialize`] signed with `signers_seeds`.
+pub fn initialize_signed<'a>(
+    program: &AccountInfo<'a>,
+    user: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::Initialize,
+            0,
+            instruction::initialize_accounts(user.key, ledger.key, vault.key),
+        ),
+        &[
+            user.clone(),
+            ledger.clone(),
+            vault.clone(),
+            system_program.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Create the ledger account of `user` with a history of its last `capacity` operations,
+/// and its vault, paid for by `user`.
+pub fn initialize_with_history<'a>(
+    program: &AccountInfo<'a>,
+    user: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    capacity: u8,
+) -> ProgramResult {
+    initialize_with_history_signed(program, user, ledger, vault, system_program, capacity, &[])
+}
+
+/// [`initialize_with_history`] signed with `signers_seeds`.
+pub fn initialize_with_history_signed<'a>(
+    program: &AccountInfo<'a>,
+    user: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    capacity: u8,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::InitializeWithHistory { capacity },
+            0,
+            instruction::initialize_accounts(user.key, ledger.key, vault.key),
+        ),
+        &[
+            user.clone(),
+            ledger.clone(),
+            vault.clone(),
+            system_program.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Deposit `amount` lamports from `depositor` into `ledger`.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    depositor: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+) -> ProgramResult {
+    deposit_signed(
+        program,
+        ledger,
+        vault,
+        depositor,
+        system_program,
+        config,
+        amount,
+        &[],
+    )
+}
+
+/// [`deposit`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    depositor: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::Deposit { amount },
+            0,
+            instruction::deposit_accounts(ledger.key, vault.key, depositor.key, config.key),
+        ),
+        &[
+            ledger.clone(),
+            vault.clone(),
+            depositor.clone(),
+            system_program.clone(),
+            config.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Withdraw `amount` lamports from `ledger` to its `authority`, or to its delegate
+/// passed as `authority`.
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    fee_vault: &AccountInfo<'a>,
+    amount: u64,
+    nonce: u64,
+) -> ProgramResult {
+    withdraw_signed(
+        program,
+        ledger,
+        vault,
+        authority,
+        config,
+        fee_vault,
+        amount,
+        nonce,
+        &[],
+    )
+}
+
+/// [`withdraw`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    fee_vault: &AccountInfo<'a>,
+    amount: u64,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::Withdraw { amount },
+            nonce,
+            instruction::withdraw_accounts(
+                ledger.key,
+                vault.key,
+                authority.key,
+                config.key,
+                fee_vault.key,
+            ),
+        ),
+        &[
+            ledger.clone(),
+            vault.clone(),
+            authority.clone(),
+            config.clone(),
+            fee_vault.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Return the balance of `ledger`.
+///
+/// Nothing signs the instruction, so there is no `_signed` variant.
+pub fn check_balance<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+) -> Result<u64, ProgramError> {
+    invoke(
+        &instruction::check_balance(program.key, ledger.key),
+        &[ledger.clone(), program.clone()],
+    )?;
+    // A zero balance leaves no return data, as the runtime drops trailing zero bytes
+    let data = match get_return_data() {
+        Some((program_id, data)) if program_id == *program.key => data,
+        _ => Vec::new(),
+    };
+    unpack_balance(&data).ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Deposit `amount` tokens of `mint` from the `source` token account of `depositor`.
+///
+/// `depositor` also pays for the token vault the first time `mint` is deposited.
+#[allow(clippy::too_many_arguments)]
+pub fn token_deposit<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    token_vault: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    depositor: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+) -> ProgramResult {
+    token_deposit_signed(
+        program,
+        ledger,
+        vault,
+        token_vault,
+        mint,
+        depositor,
+        source,
+        token_program,
+        system_program,
+        config,
+        amount,
+        &[],
+    )
+}
+
+/// [`token_deposit`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn token_deposit_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    token_vault: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    depositor: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::TokenDeposit { amount },
+            0,
+            instruction::token_deposit_accounts(
+                ledger.key,
+                vault.key,
+                token_vault.key,
+                mint.key,
+                depositor.key,
+                source.key,
+                config.key,
+            ),
+        ),
+        &[
+            ledger.clone(),
+            vault.clone(),
+            token_vault.clone(),
+            mint.clone(),
+            depositor.clone(),
+            source.clone(),
+            token_program.clone(),
+            system_program.clone(),
+            config.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Withdraw `amount` tokens of `mint` from `ledger` to the `destination` token account.
+#[allow(clippy::too_many_arguments)]
+pub fn token_withdraw<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    token_vault: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+    nonce: u64,
+) -> ProgramResult {
+    token_withdraw_signed(
+        program,
+        ledger,
+        vault,
+        token_vault,
+        mint,
+        authority,
+        destination,
+        token_program,
+        config,
+        amount,
+        nonce,
+        &[],
+    )
+}
+
+/// [`token_withdraw`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn token_withdraw_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    token_vault: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::TokenWithdraw { amount },
+            nonce,
+            instruction::token_withdraw_accounts(
+                ledger.key,
+                vault.key,
+                token_vault.key,
+                mint.key,
+                authority.key,
+                destination.key,
+                config.key,
+            ),
+        ),
+        &[
+            ledger.clone(),
+            vault.clone(),
+            token_vault.clone(),
+            mint.clone(),
+            authority.clone(),
+            destination.clone(),
+            token_program.clone(),
+            config.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Transfer `amount` lamports from the `source` ledger to the `destination` ledger.
+///
+/// `nonce` is the sequence number of `source`.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer<'a>(
+    program: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    source_vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    destination_vault: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+    nonce: u64,
+) -> ProgramResult {
+    transfer_signed(
+        program,
+        source,
+        source_vault,
+        authority,
+        destination,
+        destination_vault,
+        config,
+        amount,
+        nonce,
+        &[],
+    )
+}
+
+/// [`transfer`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_signed<'a>(
+    program: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    source_vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    destination_vault: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    amount: u64,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::Transfer { amount },
+            nonce,
+            instruction::transfer_accounts(
+                source.key,
+                source_vault.key,
+                authority.key,
+                destination.key,
+                destination_vault.key,
+                config.key,
+            ),
+        ),
+        &[
+            source.clone(),
+            source_vault.clone(),
+            authority.clone(),
+            destination.clone(),
+            destination_vault.clone(),
+            config.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Migrate the version 1 `ledger` account to the current layout.
+///
+/// The `ledger` keypair must sign, and `authority` becomes the authority of the
+/// migrated account and pays for its vault.
+pub fn migrate<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> ProgramResult {
+    migrate_signed(program, ledger, authority, vault, system_program, &[])
+}
+
+/// [`migrate`] signed with `signers_seeds`.
+pub fn migrate_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::Migrate,
+            0,
+            instruction::migrate_accounts(ledger.key, authority.key, vault.key),
+        ),
+        &[
+            ledger.clone(),
+            authority.clone(),
+            vault.clone(),
+            system_program.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Migrate `ledger`, created by `initialize` with an older layout, to the current layout.
+///
+/// Unlike `migrate` the ledger does not sign, and `payer` only pays for the larger account.
+pub fn upgrade<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> ProgramResult {
+    upgrade_signed(program, ledger, payer, vault, system_program, &[])
+}
+
+/// [`upgrade`] signed with `signers_seeds`.
+pub fn upgrade_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut instruction = command_instruction(
+        program.key,
+        Command::Migrate,
+        0,
+        instruction::migrate_accounts(ledger.key, payer.key, vault.key),
+    );
+    instruction.accounts[0].is_signer = false;
+    invoke_signed(
+        &instruction,
+        &[
+            ledger.clone(),
+            payer.clone(),
+            vault.clone(),
+            system_program.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Close `ledger`, sweeping its balance to `authority` and its rent to `destination`.
+///
+/// `token_accounts` are the SPL Token program then the token vaults of `ledger` in the
+/// order of `Data::tokens`, or empty if it never held tokens.
+#[allow(clippy::too_many_arguments)]
+pub fn close<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    fee_vault: &AccountInfo<'a>,
+    token_accounts: &[AccountInfo<'a>],
+    nonce: u64,
+) -> ProgramResult {
+    close_signed(
+        program,
+        ledger,
+        vault,
+        authority,
+        destination,
+        config,
+        fee_vault,
+        token_accounts,
+        nonce,
+        &[],
+    )
+}
+
+/// [`close`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn close_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    fee_vault: &AccountInfo<'a>,
+    token_accounts: &[AccountInfo<'a>],
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut accounts = instruction::close_accounts(
+        ledger.key,
+        vault.key,
+        authority.key,
+        destination.key,
+        config.key,
+        fee_vault.key,
+    );
+    accounts.extend(token_accounts.iter().map(|account| AccountMeta {
+        pubkey: *account.key,
+        is_signer: false,
+        is_writable: account.is_writable,
+    }));
+    let instruction = command_instruction(program.key, Command::Close, nonce, accounts);
+    let mut account_infos = vec![
+        ledger.clone(),
+        vault.clone(),
+        authority.clone(),
+        destination.clone(),
+        config.clone(),
+        fee_vault.clone(),
+    ];
+    account_infos.extend_from_slice(token_accounts);
+    account_infos.push(program.clone());
+    invoke_signed(&instruction, &account_infos, signers_seeds)
+}
+
+/// Let `delegate` withdraw up to `amount` from `ledger`.
+pub fn approve<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    delegate: &Pubkey,
+    amount: u64,
+    nonce: u64,
+) -> ProgramResult {
+    approve_signed(program, ledger, authority, delegate, amount, nonce, &[])
+}
+
+/// [`approve`] signed with `signers_seeds`.
+pub fn approve_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    delegate: &Pubkey,
+    amount: u64,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &instruction::approve(
+            program.key,
+            ledger.key,
+            authority.key,
+            delegate,
+            amount,
+            nonce,
+        ),
+        &[ledger.clone(), authority.clone(), program.clone()],
+        signers_seeds,
+    )
+}
+
+/// Remove the delegate of `ledger`.
+pub fn revoke<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    nonce: u64,
+) -> ProgramResult {
+    revoke_signed(program, ledger, authority, nonce, &[])
+}
+
+/// [`revoke`] signed with `signers_seeds`.
+pub fn revoke_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &instruction::revoke(program.key, ledger.key, authority.key, nonce),
+        &[ledger.clone(), authority.clone(), program.clone()],
+        signers_seeds,
+    )
+}
+
+/// Create the multisig account `multisig` requiring `threshold` of `signers` for the
+/// ledgers it governs. `multisig` signs, as a new keypair or a program-derived address.
+pub fn create_multisig<'a>(
+    program: &AccountInfo<'a>,
+    multisig: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    signers: &[AccountInfo<'a>],
+    threshold: u8,
+) -> ProgramResult {
+    create_multisig_signed(
+        program,
+        multisig,
+        payer,
+        system_program,
+        signers,
+        threshold,
+        &[],
+    )
+}
+
+/// [`create_multisig`] signed with `signers_seeds`.
+pub fn create_multisig_signed<'a>(
+    program: &AccountInfo<'a>,
+    multisig: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    signers: &[AccountInfo<'a>],
+    threshold: u8,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let signer_keys: Vec<Pubkey> = signers.iter().map(|signer| *signer.key).collect();
+    let mut account_infos = vec![multisig.clone(), payer.clone(), system_program.clone()];
+    account_infos.extend_from_slice(signers);
+    account_infos.push(program.clone());
+    invoke_signed(
+        &instruction::create_multisig(
+            program.key,
+            multisig.key,
+            payer.key,
+            &signer_keys,
+            threshold,
+        ),
+        &account_infos,
+        signers_seeds,
+    )
+}
+
+/// Hand `ledger` over from `authority` to `new_authority`.
+pub fn set_authority<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    new_authority: &Pubkey,
+    nonce: u64,
+) -> ProgramResult {
+    set_authority_signed(program, ledger, authority, new_authority, nonce, &[])
+}
+
+/// [`set_authority`] signed with `signers_seeds`.
+pub fn set_authority_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    new_authority: &Pubkey,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &instruction::set_authority(program.key, ledger.key, authority.key, new_authority, nonce),
+        &[ledger.clone(), authority.clone(), program.clone()],
+        signers_seeds,
+    )
+}
+
+/// Deposit `amount` lamports from `depositor` into `ledger`, locked until `vesting`
+/// releases them. The `authority` of the ledger must sign as well.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit_locked<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    depositor: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    amount: u64,
+    vesting: VestingSchedule,
+    nonce: u64,
+) -> ProgramResult {
+    deposit_locked_signed(
+        program,
+        ledger,
+        vault,
+        depositor,
+        system_program,
+        config,
+        authority,
+        amount,
+        vesting,
+        nonce,
+        &[],
+    )
+}
+
+/// [`deposit_locked`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit_locked_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    depositor: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    amount: u64,
+    vesting: VestingSchedule,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut accounts =
+        instruction::deposit_accounts(ledger.key, vault.key, depositor.key, config.key);
+    accounts.push(AccountMeta::new_readonly(*authority.key, true));
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::DepositLocked { amount, vesting },
+            nonce,
+            accounts,
+        ),
+        &[
+            ledger.clone(),
+            vault.clone(),
+            depositor.clone(),
+            system_program.clone(),
+            config.clone(),
+            authority.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Replace the withdrawal limit of `ledger` with `limit`, after `LIMIT_CHANGE_DELAY` unless
+/// `limit` is within the current limit.
+pub fn set_withdrawal_limit<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    limit: WithdrawalLimit,
+    nonce: u64,
+) -> ProgramResult {
+    set_withdrawal_limit_signed(program, ledger, authority, limit, nonce, &[])
+}
+
+/// [`set_withdrawal_limit`] signed with `signers_seeds`.
+pub fn set_withdrawal_limit_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    limit: WithdrawalLimit,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &instruction::set_withdrawal_limit(program.key, ledger.key, authority.key, limit, nonce),
+        &[ledger.clone(), authority.clone(), program.clone()],
+        signers_seeds,
+    )
+}
+
+/// Create the config account with `admin`, the upgrade authority of the program, as admin
+/// and treasury, and the fee vault, paid for by `admin`.
+pub fn initialize_config<'a>(
+    program: &AccountInfo<'a>,
+    admin: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    program_data: &AccountInfo<'a>,
+    fee_vault: &AccountInfo<'a>,
+) -> ProgramResult {
+    initialize_config_signed(
+        program,
+        admin,
+        config,
+        system_program,
+        program_data,
+        fee_vault,
+        &[],
+    )
+}
+
+/// [`initialize_config`] signed with `signers_seeds`.
+pub fn initialize_config_signed<'a>(
+    program: &AccountInfo<'a>,
+    admin: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    program_data: &AccountInfo<'a>,
+    fee_vault: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::InitializeConfig,
+            0,
+            instruction::initialize_config_accounts(
+                admin.key,
+                config.key,
+                program_data.key,
+                fee_vault.key,
+            ),
+        ),
+        &[
+            admin.clone(),
+            config.clone(),
+            system_program.clone(),
+            program_data.clone(),
+            fee_vault.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Replace the pause switch, withdrawal fee and treasury of the config. A fee increase
+/// takes effect after `FEE_CHANGE_DELAY`.
+pub fn update_config<'a>(
+    program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    admin: &AccountInfo<'a>,
+    paused: bool,
+    fee_bps: u16,
+    treasury: &Pubkey,
+) -> ProgramResult {
+    update_config_signed(program, config, admin, paused, fee_bps, treasury, &[])
+}
+
+/// [`update_config`] signed with `signers_seeds`.
+pub fn update_config_signed<'a>(
+    program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    admin: &AccountInfo<'a>,
+    paused: bool,
+    fee_bps: u16,
+    treasury: &Pubkey,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::UpdateConfig {
+                paused,
+                fee_bps,
+                treasury: *treasury,
+            },
+            0,
+            instruction::update_config_accounts(config.key, admin.key),
+        ),
+        &[config.clone(), admin.clone(), program.clone()],
+        signers_seeds,
+    )
+}
+
+/// Hand the admin role of the config over from `admin` to `new_admin`.
+pub fn set_admin<'a>(
+    program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    admin: &AccountInfo<'a>,
+    new_admin: &Pubkey,
+) -> ProgramResult {
+    set_admin_signed(program, config, admin, new_admin, &[])
+}
+
+/// [`set_admin`] signed with `signers_seeds`.
+pub fn set_admin_signed<'a>(
+    program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    admin: &AccountInfo<'a>,
+    new_admin: &Pubkey,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::SetAdmin { admin: *new_admin },
+            0,
+            instruction::update_config_accounts(config.key, admin.key),
+        ),
+        &[config.clone(), admin.clone(), program.clone()],
+        signers_seeds,
+    )
+}
+
+/// Send the collected withdrawal fees to `treasury`, the treasury of the config.
+///
+/// Nothing signs the instruction, so there is no `_signed` variant.
+pub fn collect_fees<'a>(
+    program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    fee_vault: &AccountInfo<'a>,
+    treasury: &AccountInfo<'a>,
+) -> ProgramResult {
+    invoke(
+        &command_instruction(
+            program.key,
+            Command::CollectFees,
+            0,
+            instruction::collect_fees_accounts(config.key, fee_vault.key, treasury.key),
+        ),
+        &[
+            config.clone(),
+            fee_vault.clone(),
+            treasury.clone(),
+            program.clone(),
+        ],
+    )
+}
+
+/// Move `terms.amount` lamports from `ledger` into the new `escrow` account, whose rent
+/// `payer` pays until the escrow is released or refunded.
+#[allow(clippy::too_many_arguments)]
+pub fn create_escrow<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    terms: EscrowTerms,
+    nonce: u64,
+) -> ProgramResult {
+    create_escrow_signed(
+        program,
+        ledger,
+        vault,
+        authority,
+        escrow,
+        payer,
+        system_program,
+        config,
+        terms,
+        nonce,
+        &[],
+    )
+}
+
+/// [`create_escrow`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn create_escrow_signed<'a>(
+    program: &AccountInfo<'a>,
+    ledger: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    terms: EscrowTerms,
+    nonce: u64,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::CreateEscrow { terms },
+            nonce,
+            instruction::create_escrow_accounts(
+                ledger.key,
+                vault.key,
+                authority.key,
+                escrow.key,
+                payer.key,
+                config.key,
+            ),
+        ),
+        &[
+            ledger.clone(),
+            vault.clone(),
+            authority.clone(),
+            escrow.clone(),
+            payer.clone(),
+            system_program.clone(),
+            config.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Pay `escrow` out to its `recipient` ledger, signed by `releaser`. `source` is the
+/// ledger the escrow was created from.
+#[allow(clippy::too_many_arguments)]
+pub fn release_escrow<'a>(
+    program: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    recipient: &AccountInfo<'a>,
+    recipient_vault: &AccountInfo<'a>,
+    releaser: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+) -> ProgramResult {
+    release_escrow_signed(
+        program,
+        escrow,
+        recipient,
+        recipient_vault,
+        releaser,
+        payer,
+        config,
+        source,
+        &[],
+    )
+}
+
+/// [`release_escrow`] signed with `signers_seeds`.
+#[allow(clippy::too_many_arguments)]
+pub fn release_escrow_signed<'a>(
+    program: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    recipient: &AccountInfo<'a>,
+    recipient_vault: &AccountInfo<'a>,
+    releaser: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &command_instruction(
+            program.key,
+            Command::ReleaseEscrow,
+            0,
+            instruction::release_escrow_accounts(
+                escrow.key,
+                recipient.key,
+                recipient_vault.key,
+                releaser.key,
+                payer.key,
+                config.key,
+                source.key,
+            ),
+        ),
+        &[
+            escrow.clone(),
+            recipient.clone(),
+            recipient_vault.clone(),
+            releaser.clone(),
+            payer.clone(),
+            config.clone(),
+            source.clone(),
+            program.clone(),
+        ],
+        signers_seeds,
+    )
+}
+
+/// Pay the expired `escrow` back to its `source` ledger.
+///
+/// Nothing signs the instruction, so there is no `_signed` variant.
+pub fn refund_escrow<'a>(
+    program: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    source_vault: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    config: &AccountInfo<'a>,
+) -> ProgramResult {
+    invoke(
+        &command_instruction(
+            program.key,
+            Command::RefundEscrow,
+            0,
+            instruction::refund_escrow_accounts(
+                escrow.key,
+                source.key,
+                source_vault.key,
+                payer.key,
+                config.key,
+            ),
+        ),
+        &[
+            escrow.clone(),
+            source.clone(),
+            source_vault.clone(),
+            payer.clone(),
+            config.clone(),
+            program.clone(),
+        ],
+    )
+}
@@ -0,0 +1,6 @@
+//! Program entrypoint, left out when the crate is built with `no-entrypoint`.
+
+use crate::process_instruction;
+use solana_program::entrypoint;
+
+entrypoint!(process_instruction);
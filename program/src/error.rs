@@ -0,0 +1,235 @@
+use solana_program::{instruction::InstructionError, program_error::ProgramError};
+use std::fmt;
+
+/// Errors returned by the program as `ProgramError::Custom(code)`.
+///
+/// The discriminant of each variant is its error code and must never change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum BankError {
+    /// The instruction data could not be decoded
+    InvalidInstruction = 0,
+    /// Deposits, withdrawals and transfers must move a non-zero amount
+    ZeroAmount = 1,
+    /// A balance would exceed `u64::MAX`
+    Overflow = 2,
+    /// The balance is lower than the requested amount
+    InsufficientFunds = 3,
+    /// The account is not owned by this program
+    InvalidAccountOwner = 4,
+    /// The instruction was built for another program id
+    ProgramIdMismatch = 5,
+    /// The ledger account must be writable
+    AccountNotWritable = 6,
+    /// The ledger account has not been created with `Command::Initialize`
+    UninitializedAccount = 7,
+    /// The ledger account already exists
+    AlreadyInitialized = 8,
+    /// The ledger account data could not be decoded
+    InvalidAccountData = 9,
+    /// The signer is not the authority of the ledger account
+    WrongAuthority = 10,
+    /// The ledger account is not at the address derived for the user
+    InvalidLedgerAddress = 11,
+    /// The vault is not the one derived for the ledger account
+    InvalidVault = 12,
+    /// The token vault is not the one derived for the ledger account and mint
+    InvalidTokenVault = 13,
+    /// Source and destination of a transfer are the same account
+    SelfTransfer = 14,
+    /// The ledger account uses an older layout and must be migrated with `Command::Migrate`
+    AccountNeedsMigration = 15,
+    /// The ledger account uses a layout version this program does not know
+    UnsupportedAccountVersion = 16,
+    /// The ledger account already uses the current layout
+    AlreadyMigrated = 17,
+    /// The instruction nonce does not match the sequence number of the ledger account
+    InvalidNonce = 18,
+    /// The ledger account still holds tokens
+    TokenBalanceNotZero = 19,
+    /// The destination is one of the accounts being closed
+    InvalidDestination = 20,
+    /// The delegate's remaining allowance is lower than the requested amount
+    AllowanceExceeded = 21,
+    /// The multisig threshold is out of range or its signers are not distinct
+    InvalidMultisig = 22,
+    /// Fewer multisig members signed than its threshold
+    NotEnoughSigners = 23,
+    /// The amount exceeds what the vesting schedule has released
+    FundsLocked = 24,
+    /// The vesting schedule is not ordered, or conflicts with the active one
+    InvalidVestingSchedule = 25,
+    /// A withdrawal limit needs a positive window
+    InvalidWithdrawalLimit = 26,
+    /// The amount exceeds what the withdrawal limit leaves in the current window
+    WithdrawalLimitExceeded = 27,
+    /// Deposits and withdrawals are paused by the admin
+    Paused = 28,
+    /// The config account is not the one derived for the program, or a setting is out of range
+    InvalidConfig = 29,
+    /// The signer is not the admin of the config account
+    NotAdmin = 30,
+    /// The account is not the treasury of the config account
+    InvalidTreasury = 31,
+    /// The escrow account or its terms are invalid, or do not match the accounts passed
+    InvalidEscrow = 32,
+    /// The escrow can no longer be released, its expiry slot was reached
+    EscrowExpired = 33,
+    /// The escrow can't be refunded before its expiry slot
+    EscrowNotExpired = 34,
+    /// The signer may not release the escrow
+    NotEscrowReleaser = 35,
+    /// The history length is zero or above `MAX_HISTORY_LEN`
+    InvalidHistoryLength = 36,
+    /// The account is not the program data account of this program
+    InvalidProgramData = 37,
+    /// The ledger account still has escrows to release or refund
+    EscrowsOpen = 38,
+    /// The fee vault is not the one derived with `find_fee_vault_address`
+    InvalidFeeVault = 39,
+}
+
+impl BankError {
+    /// Every error, in code order.
+    pub const ALL: [BankError; 40] = [
+        BankError::InvalidInstruction,
+        BankError::ZeroAmount,
+        BankError::Overflow,
+        BankError::InsufficientFunds,
+        BankError::InvalidAccountOwner,
+        BankError::ProgramIdMismatch,
+        BankError::AccountNotWritable,
+        BankError::UninitializedAccount,
+        BankError::AlreadyInitialized,
+        BankError::InvalidAccountData,
+        BankError::WrongAuthority,
+        BankError::InvalidLedgerAddress,
+        BankError::InvalidVault,
+        BankError::InvalidTokenVault,
+        BankError::SelfTransfer,
+        BankError::AccountNeedsMigration,
+        BankError::UnsupportedAccountVersion,
+        BankError::AlreadyMigrated,
+        BankError::InvalidNonce,
+        BankError::TokenBalanceNotZero,
+        BankError::InvalidDestination,
+        BankError::AllowanceExceeded,
+        BankError::InvalidMultisig,
+        BankError::NotEnoughSigners,
+        BankError::FundsLocked,
+        BankError::InvalidVestingSchedule,
+        BankError::InvalidWithdrawalLimit,
+        BankError::WithdrawalLimitExceeded,
+        BankError::Paused,
+        BankError::InvalidConfig,
+        BankError::NotAdmin,
+        BankError::InvalidTreasury,
+        BankError::InvalidEscrow,
+        BankError::EscrowExpired,
+        BankError::EscrowNotExpired,
+        BankError::NotEscrowReleaser,
+        BankError::InvalidHistoryLength,
+        BankError::InvalidProgramData,
+        BankError::EscrowsOpen,
+        BankError::InvalidFeeVault,
+    ];
+
+    /// Error code carried by `ProgramError::Custom`.
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Look up the error with the given code.
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+
+    /// Decode the error of a failed instruction, e.g. the `InstructionError` of a
+    /// `TransactionError::InstructionError(index, error)`.
+    ///
+    /// Returns `None` for errors that were not raised by this program.
+    pub fn from_instruction_error(error: &InstructionError) -> Option<Self> {
+        match error {
+            InstructionError::Custom(code) => Self::from_code(*code),
+            _ => None,
+        }
+    }
+}
+
+impl From<BankError> for ProgramError {
+    fn from(error: BankError) -> Self {
+        ProgramError::Custom(error.code())
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            BankError::InvalidInstruction => "invalid instruction data",
+            BankError::ZeroAmount => "amount must be greater than zero",
+            BankError::Overflow => "balance overflow",
+            BankError::InsufficientFunds => "insufficient funds",
+            BankError::InvalidAccountOwner => "account is not owned by the program",
+            BankError::ProgramIdMismatch => "instruction was built for another program",
+            BankError::AccountNotWritable => "ledger account is not writable",
+            BankError::UninitializedAccount => "ledger account is not initialized",
+            BankError::AlreadyInitialized => "ledger account is already initialized",
+            BankError::InvalidAccountData => "invalid ledger account data",
+            BankError::WrongAuthority => "signer is not the ledger authority",
+            BankError::InvalidLedgerAddress => "invalid ledger account address",
+            BankError::InvalidVault => "invalid vault account",
+            BankError::InvalidTokenVault => "invalid token vault account",
+            BankError::SelfTransfer => "cannot transfer to the same ledger account",
+            BankError::AccountNeedsMigration => "ledger account must be migrated first",
+            BankError::UnsupportedAccountVersion => "unsupported ledger account version",
+            BankError::AlreadyMigrated => "ledger account is already migrated",
+            BankError::InvalidNonce => "nonce does not match the ledger sequence number",
+            BankError::TokenBalanceNotZero => "ledger account still holds tokens",
+            BankError::InvalidDestination => "destination is an account being closed",
+            BankError::AllowanceExceeded => "delegated allowance exceeded",
+            BankError::InvalidMultisig => "invalid multisig threshold or signers",
+            BankError::NotEnoughSigners => "not enough multisig signers",
+            BankError::FundsLocked => "funds are still locked",
+            BankError::InvalidVestingSchedule => "invalid vesting schedule",
+            BankError::InvalidWithdrawalLimit => "invalid withdrawal limit",
+            BankError::WithdrawalLimitExceeded => "withdrawal limit exceeded",
+            BankError::Paused => "program is paused",
+            BankError::InvalidConfig => "invalid config account or settings",
+            BankError::NotAdmin => "signer is not the config admin",
+            BankError::InvalidTreasury => "invalid treasury account",
+            BankError::InvalidEscrow => "invalid escrow account or terms",
+            BankError::EscrowExpired => "escrow has expired",
+            BankError::EscrowNotExpired => "escrow has not expired yet",
+            BankError::NotEscrowReleaser => "signer cannot release the escrow",
+            BankError::InvalidHistoryLength => "invalid history length",
+            BankError::InvalidProgramData => "invalid program data account",
+            BankError::EscrowsOpen => "ledger account still has open escrows",
+            BankError::InvalidFeeVault => "invalid fee vault account",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for BankError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_round_trip() {
+        for (code, error) in BankError::ALL.iter().enumerate() {
+            assert_eq!(error.code(), code as u32);
+            assert_eq!(BankError::from_code(code as u32), Some(*error));
+            assert_eq!(
+                BankError::from_instruction_error(&InstructionError::Custom(code as u32)),
+                Some(*error)
+            );
+        }
+        assert_eq!(BankError::from_code(BankError::ALL.len() as u32), None);
+        assert_eq!(
+            BankError::from_instruction_error(&InstructionError::InvalidArgument),
+            None
+        );
+    }
+}
@@ -0,0 +1,656 @@
+//! Events logged by the program with `sol_log_data`, and their decoding from the log
+//! messages of a transaction.
+//!
+//! Each event is a single `Program data:` record holding a one-byte tag followed by
+//! its fields: public keys as 32 bytes, integers and timestamps in little-endian.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use solana_program::{log::sol_log_data, pubkey::Pubkey};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A ledger account was created for `authority`
+    Initialized { ledger: Pubkey, authority: Pubkey },
+    /// `amount` lamports were deposited, leaving `balance`
+    Deposited {
+        ledger: Pubkey,
+        amount: u64,
+        balance: u64,
+    },
+    /// `amount` lamports were withdrawn, leaving `balance`
+    Withdrew {
+        ledger: Pubkey,
+        amount: u64,
+        balance: u64,
+    },
+    /// `amount` lamports moved from the `source` ledger to the `destination` ledger
+    Transferred {
+        source: Pubkey,
+        destination: Pubkey,
+        amount: u64,
+    },
+    /// The ledger was closed, `balance` went to its authority and the rent to `destination`
+    Closed {
+        ledger: Pubkey,
+        destination: Pubkey,
+        balance: u64,
+    },
+    /// `delegate` may withdraw up to `amount`
+    Approved {
+        ledger: Pubkey,
+        delegate: Pubkey,
+        amount: u64,
+    },
+    /// The delegate was removed
+    Revoked { ledger: Pubkey },
+    /// The ledger was handed over to `authority`
+    AuthorityChanged { ledger: Pubkey, authority: Pubkey },
+    /// `amount` lamports are now locked, vesting from `start` to `end` after `cliff`
+    Locked {
+        ledger: Pubkey,
+        amount: u64,
+        start: i64,
+        cliff: i64,
+        end: i64,
+    },
+    /// The withdrawal limit becomes `amount` per `window` seconds at `effective_at`
+    WithdrawalLimitScheduled {
+        ledger: Pubkey,
+        amount: u64,
+        window: i64,
+        effective_at: i64,
+    },
+    /// `amount` lamports moved from the `source` ledger into `escrow` for the `recipient`
+    /// ledger, refundable from `expiry_slot`
+    EscrowCreated {
+        escrow: Pubkey,
+        source: Pubkey,
+        recipient: Pubkey,
+        amount: u64,
+        expiry_slot: u64,
+    },
+    /// The `amount` held by `escrow` went to the `recipient` ledger
+    EscrowReleased {
+        escrow: Pubkey,
+        recipient: Pubkey,
+        amount: u64,
+    },
+    /// The `amount` held by `escrow` went back to the `source` ledger
+    EscrowRefunded {
+        escrow: Pubkey,
+        source: Pubkey,
+        amount: u64,
+    },
+    /// `amount` tokens of `mint` were deposited, leaving `balance`
+    TokenDeposited {
+        ledger: Pubkey,
+        mint: Pubkey,
+        amount: u64,
+        balance: u64,
+    },
+    /// `amount` tokens of `mint` were withdrawn, leaving `balance`
+    TokenWithdrew {
+        ledger: Pubkey,
+        mint: Pubkey,
+        amount: u64,
+        balance: u64,
+    },
+    /// The ledger was migrated from layout `from_version` to `to_version`
+    Migrated {
+        ledger: Pubkey,
+        from_version: u8,
+        to_version: u8,
+    },
+    /// A multisig of `signers` members was created, `threshold` of which must sign
+    MultisigCreated {
+        multisig: Pubkey,
+        threshold: u8,
+        signers: u8,
+    },
+    /// The config was created with `admin` as admin and treasury
+    ConfigCreated { config: Pubkey, admin: Pubkey },
+    /// The settings of the config were replaced, the fee taking effect at `fee_effective_at`
+    ConfigUpdated {
+        config: Pubkey,
+        paused: bool,
+        fee_bps: u16,
+        fee_effective_at: i64,
+        treasury: Pubkey,
+    },
+    /// The admin role of the config was handed over to `admin`
+    AdminChanged { config: Pubkey, admin: Pubkey },
+    /// `amount` lamports of fees went to `treasury`
+    FeesCollected { treasury: Pubkey, amount: u64 },
+}
+
+impl Event {
+    /// Encode the event as logged by `emit`.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(1 + 32 + 32 + 8);
+        match self {
+            Event::Initialized { ledger, authority } => {
+                data.push(0);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(authority.as_ref());
+            }
+            Event::Deposited {
+                ledger,
+                amount,
+                balance,
+            } => {
+                data.push(1);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.extend_from_slice(&balance.to_le_bytes());
+            }
+            Event::Withdrew {
+                ledger,
+                amount,
+                balance,
+            } => {
+                data.push(2);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.extend_from_slice(&balance.to_le_bytes());
+            }
+            Event::Transferred {
+                source,
+                destination,
+                amount,
+            } => {
+                data.push(3);
+                data.extend_from_slice(source.as_ref());
+                data.extend_from_slice(destination.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::Closed {
+                ledger,
+                destination,
+                balance,
+            } => {
+                data.push(4);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(destination.as_ref());
+                data.extend_from_slice(&balance.to_le_bytes());
+            }
+            Event::Approved {
+                ledger,
+                delegate,
+                amount,
+            } => {
+                data.push(5);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(delegate.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::Revoked { ledger } => {
+                data.push(6);
+                data.extend_from_slice(ledger.as_ref());
+            }
+            Event::AuthorityChanged { ledger, authority } => {
+                data.push(7);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(authority.as_ref());
+            }
+            Event::Locked {
+                ledger,
+                amount,
+                start,
+                cliff,
+                end,
+            } => {
+                data.push(8);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.extend_from_slice(&start.to_le_bytes());
+                data.extend_from_slice(&cliff.to_le_bytes());
+                data.extend_from_slice(&end.to_le_bytes());
+            }
+            Event::WithdrawalLimitScheduled {
+                ledger,
+                amount,
+                window,
+                effective_at,
+            } => {
+                data.push(9);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.extend_from_slice(&window.to_le_bytes());
+                data.extend_from_slice(&effective_at.to_le_bytes());
+            }
+            Event::EscrowCreated {
+                escrow,
+                source,
+                recipient,
+                amount,
+                expiry_slot,
+            } => {
+                data.push(10);
+                data.extend_from_slice(escrow.as_ref());
+                data.extend_from_slice(source.as_ref());
+                data.extend_from_slice(recipient.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.extend_from_slice(&expiry_slot.to_le_bytes());
+            }
+            Event::EscrowReleased {
+                escrow,
+                recipient,
+                amount,
+            } => {
+                data.push(11);
+                data.extend_from_slice(escrow.as_ref());
+                data.extend_from_slice(recipient.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::EscrowRefunded {
+                escrow,
+                source,
+                amount,
+            } => {
+                data.push(12);
+                data.extend_from_slice(escrow.as_ref());
+                data.extend_from_slice(source.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::TokenDeposited {
+                ledger,
+                mint,
+                amount,
+                balance,
+            } => {
+                data.push(13);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(mint.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.extend_from_slice(&balance.to_le_bytes());
+            }
+            Event::TokenWithdrew {
+                ledger,
+                mint,
+                amount,
+                balance,
+            } => {
+                data.push(14);
+                data.extend_from_slice(ledger.as_ref());
+                data.extend_from_slice(mint.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.extend_from_slice(&balance.to_le_bytes());
+            }
+            Event::Migrated {
+                ledger,
+                from_version,
+                to_version,
+            } => {
+                data.push(15);
+                data.extend_from_slice(ledger.as_ref());
+                data.push(*from_version);
+                data.push(*to_version);
+            }
+            Event::MultisigCreated {
+                multisig,
+                threshold,
+                signers,
+            } => {
+                data.push(16);
+                data.extend_from_slice(multisig.as_ref());
+                data.push(*threshold);
+                data.push(*signers);
+            }
+            Event::ConfigCreated { config, admin } => {
+                data.push(17);
+                data.extend_from_slice(config.as_ref());
+                data.extend_from_slice(admin.as_ref());
+            }
+            Event::ConfigUpdated {
+                config,
+                paused,
+                fee_bps,
+                fee_effective_at,
+                treasury,
+            } => {
+                data.push(18);
+                data.extend_from_slice(config.as_ref());
+                data.push(u8::from(*paused));
+                data.extend_from_slice(&fee_bps.to_le_bytes());
+                data.extend_from_slice(&fee_effective_at.to_le_bytes());
+                data.extend_from_slice(treasury.as_ref());
+            }
+            Event::AdminChanged { config, admin } => {
+                data.push(19);
+                data.extend_from_slice(config.as_ref());
+                data.extend_from_slice(admin.as_ref());
+            }
+            Event::FeesCollected { treasury, amount } => {
+                data.push(20);
+                data.extend_from_slice(treasury.as_ref());
+                data.extend_from_slice(&amount.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    /// Decode an event encoded by `pack`.
+    pub fn unpack(input: &[u8]) -> Option<Self> {
+        let (&tag, mut rest) = input.split_first()?;
+        let event = match tag {
+            0 => Event::Initialized {
+                ledger: unpack_pubkey(&mut rest)?,
+                authority: unpack_pubkey(&mut rest)?,
+            },
+            1 => Event::Deposited {
+                ledger: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+                balance: unpack_u64(&mut rest)?,
+            },
+            2 => Event::Withdrew {
+                ledger: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+                balance: unpack_u64(&mut rest)?,
+            },
+            3 => Event::Transferred {
+                source: unpack_pubkey(&mut rest)?,
+                destination: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+            },
+            4 => Event::Closed {
+                ledger: unpack_pubkey(&mut rest)?,
+                destination: unpack_pubkey(&mut rest)?,
+                balance: unpack_u64(&mut rest)?,
+            },
+            5 => Event::Approved {
+                ledger: unpack_pubkey(&mut rest)?,
+                delegate: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+            },
+            6 => Event::Revoked {
+                ledger: unpack_pubkey(&mut rest)?,
+            },
+            7 => Event::AuthorityChanged {
+                ledger: unpack_pubkey(&mut rest)?,
+                authority: unpack_pubkey(&mut rest)?,
+            },
+            8 => Event::Locked {
+                ledger: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+                start: unpack_u64(&mut rest)? as i64,
+                cliff: unpack_u64(&mut rest)? as i64,
+                end: unpack_u64(&mut rest)? as i64,
+            },
+            9 => Event::WithdrawalLimitScheduled {
+                ledger: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+                window: unpack_u64(&mut rest)? as i64,
+                effective_at: unpack_u64(&mut rest)? as i64,
+            },
+            10 => Event::EscrowCreated {
+                escrow: unpack_pubkey(&mut rest)?,
+                source: unpack_pubkey(&mut rest)?,
+                recipient: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+                expiry_slot: unpack_u64(&mut rest)?,
+            },
+            11 => Event::EscrowReleased {
+                escrow: unpack_pubkey(&mut rest)?,
+                recipient: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+            },
+            12 => Event::EscrowRefunded {
+                escrow: unpack_pubkey(&mut rest)?,
+                source: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+            },
+            13 => Event::TokenDeposited {
+                ledger: unpack_pubkey(&mut rest)?,
+                mint: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+                balance: unpack_u64(&mut rest)?,
+            },
+            14 => Event::TokenWithdrew {
+                ledger: unpack_pubkey(&mut rest)?,
+                mint: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+                balance: unpack_u64(&mut rest)?,
+            },
+            15 => Event::Migrated {
+                ledger: unpack_pubkey(&mut rest)?,
+                from_version: unpack_u8(&mut rest)?,
+                to_version: unpack_u8(&mut rest)?,
+            },
+            16 => Event::MultisigCreated {
+                multisig: unpack_pubkey(&mut rest)?,
+                threshold: unpack_u8(&mut rest)?,
+                signers: unpack_u8(&mut rest)?,
+            },
+            17 => Event::ConfigCreated {
+                config: unpack_pubkey(&mut rest)?,
+                admin: unpack_pubkey(&mut rest)?,
+            },
+            18 => Event::ConfigUpdated {
+                config: unpack_pubkey(&mut rest)?,
+                paused: match unpack_u8(&mut rest)? {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                },
+                fee_bps: unpack_u16(&mut rest)?,
+                fee_effective_at: unpack_u64(&mut rest)? as i64,
+                treasury: unpack_pubkey(&mut rest)?,
+            },
+            19 => Event::AdminChanged {
+                config: unpack_pubkey(&mut rest)?,
+                admin: unpack_pubkey(&mut rest)?,
+            },
+            20 => Event::FeesCollected {
+                treasury: unpack_pubkey(&mut rest)?,
+                amount: unpack_u64(&mut rest)?,
+            },
+            _ => return None,
+        };
+        rest.is_empty().then_some(event)
+    }
+
+    /// Log the event from the program.
+    pub fn emit(&self) {
+        sol_log_data(&[&self.pack()]);
+    }
+}
+
+/// Decode the events logged by `program_id` from the log messages of a transaction.
+///
+/// Records logged by other programs, including those invoked by `program_id`, are
+/// skipped, as are records that do not decode.
+pub fn parse_logs<S: AsRef<str>>(program_id: &Pubkey, logs: &[S]) -> Vec<Event> {
+    let program_id = program_id.to_string();
+    // Programs currently executing, innermost last
+    let mut invoked: Vec<&str> = Vec::new();
+    let mut events = Vec::new();
+    for log in logs {
+        let log = log.as_ref();
+        if let Some(data) = log.strip_prefix("Program data: ") {
+            if invoked.last() == Some(&program_id.as_str()) {
+                events.extend(
+                    data.split(' ')
+                        .filter_map(|record| STANDARD.decode(record).ok())
+                        .filter_map(|bytes| Event::unpack(&bytes)),
+                );
+            }
+        } else if let Some(rest) = log.strip_prefix("Program ") {
+            let mut words = rest.split(' ');
+            match (words.next(), words.next()) {
+                (Some(program), Some("invoke")) => invoked.push(program),
+                (Some(_), Some("success")) | (Some(_), Some("failed:")) => {
+                    invoked.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+    events
+}
+
+fn unpack_pubkey(input: &mut &[u8]) -> Option<Pubkey> {
+    let bytes = input.get(..32)?;
+    *input = &input[32..];
+    Pubkey::try_from(bytes).ok()
+}
+
+fn unpack_u8(input: &mut &[u8]) -> Option<u8> {
+    let (&byte, rest) = input.split_first()?;
+    *input = rest;
+    Some(byte)
+}
+
+fn unpack_u16(input: &mut &[u8]) -> Option<u16> {
+    let bytes = input.get(..2)?;
+    *input = &input[2..];
+    Some(u16::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn unpack_u64(input: &mut &[u8]) -> Option<u64> {
+    let bytes = input.get(..8)?;
+    *input = &input[8..];
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_logs() {
+        let program_id = Pubkey::new_unique();
+        let other_program = Pubkey::new_unique();
+        let deposited = Event::Deposited {
+            ledger: Pubkey::new_unique(),
+            amount: 5,
+            balance: 7,
+        };
+        let transferred = Event::Transferred {
+            source: Pubkey::new_unique(),
+            destination: Pubkey::new_unique(),
+            amount: u64::MAX,
+        };
+        let closed = Event::Closed {
+            ledger: Pubkey::new_unique(),
+            destination: Pubkey::new_unique(),
+            balance: 3,
+        };
+        assert_eq!(Event::unpack(&deposited.pack()), Some(deposited));
+        assert_eq!(Event::unpack(&closed.pack()), Some(closed));
+        let locked = Event::Locked {
+            ledger: Pubkey::new_unique(),
+            amount: 8,
+            start: -1,
+            cliff: 0,
+            end: i64::MAX,
+        };
+        assert_eq!(Event::unpack(&locked.pack()), Some(locked));
+        let escrow_created = Event::EscrowCreated {
+            escrow: Pubkey::new_unique(),
+            source: Pubkey::new_unique(),
+            recipient: Pubkey::new_unique(),
+            amount: 9,
+            expiry_slot: 10,
+        };
+        assert_eq!(Event::unpack(&escrow_created.pack()), Some(escrow_created));
+        assert_eq!(Event::unpack(&[9]), None);
+        let token_deposited = Event::TokenDeposited {
+            ledger: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            amount: 11,
+            balance: 12,
+        };
+        let token_withdrew = Event::TokenWithdrew {
+            ledger: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            amount: 13,
+            balance: 0,
+        };
+        let config_updated = Event::ConfigUpdated {
+            config: Pubkey::new_unique(),
+            paused: true,
+            fee_bps: 250,
+            fee_effective_at: -14,
+            treasury: Pubkey::new_unique(),
+        };
+        let fees_collected = Event::FeesCollected {
+            treasury: Pubkey::new_unique(),
+            amount: 15,
+        };
+        for event in [
+            token_deposited,
+            token_withdrew,
+            Event::Migrated {
+                ledger: Pubkey::new_unique(),
+                from_version: 1,
+                to_version: 7,
+            },
+            Event::MultisigCreated {
+                multisig: Pubkey::new_unique(),
+                threshold: 2,
+                signers: 3,
+            },
+            Event::ConfigCreated {
+                config: Pubkey::new_unique(),
+                admin: Pubkey::new_unique(),
+            },
+            config_updated,
+            Event::AdminChanged {
+                config: Pubkey::new_unique(),
+                admin: Pubkey::new_unique(),
+            },
+            fees_collected,
+        ] {
+            assert_eq!(Event::unpack(&event.pack()), Some(event));
+        }
+        // A boolean is a single byte of 0 or 1
+        let mut invalid = config_updated.pack();
+        invalid[33] = 2;
+        assert_eq!(Event::unpack(&invalid), None);
+
+        let record = |event: &Event| format!("Program data: {}", STANDARD.encode(event.pack()));
+        let logs = vec![
+            format!("Program {} invoke [1]", program_id),
+            record(&deposited),
+            // Data logged by an invoked program belongs to that program
+            format!("Program {} invoke [2]", other_program),
+            record(&transferred),
+            format!("Program {} success", other_program),
+            "Program data: bm90IGFuIGV2ZW50".to_string(),
+            record(&transferred),
+            format!(
+                "Program {} consumed 1000 of 200000 compute units",
+                program_id
+            ),
+            format!("Program {} success", program_id),
+            format!("Program {} invoke [1]", program_id),
+            record(&token_deposited),
+            record(&config_updated),
+            format!("Program {} invoke [2]", other_program),
+            record(&fees_collected),
+            format!("Program {} success", other_program),
+            record(&token_withdrew),
+            format!("Program {} success", program_id),
+            format!("Program {} invoke [1]", other_program),
+            record(&deposited),
+            format!(
+                "Program {} failed: custom program error: 0x1",
+                other_program
+            ),
+        ];
+        assert_eq!(
+            parse_logs(&program_id, &logs),
+            vec![
+                deposited,
+                transferred,
+                token_deposited,
+                config_updated,
+                token_withdrew
+            ]
+        );
+        assert_eq!(
+            parse_logs(&other_program, &logs),
+            vec![transferred, fees_collected, deposited]
+        );
+    }
+}
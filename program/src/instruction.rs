@@ -0,0 +1,810 @@
+//! Builders for the instructions of the program.
+//!
+//! Each function encodes the account order and the signer/writable flags expected
+//! by the processor, so clients only provide the keys that vary.
+//!
+//! The `*_accounts` functions hold the account lists of the builders deriving addresses,
+//! for callers that already have every key, such as the [`crate::cpi`] wrappers.
+
+use crate::{
+    error::BankError, find_config_address, find_fee_vault_address, find_ledger_address,
+    find_token_vault_address, find_vault_address, BatchInstruction, Command, CommandInstruction,
+    EscrowTerms, VestingSchedule, WithdrawalLimit,
+};
+use solana_program::{
+    bpf_loader_upgradeable,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program,
+};
+
+pub(crate) fn command_instruction(
+    program_id: &Pubkey,
+    command: Command,
+    nonce: u64,
+    accounts: Vec<AccountMeta>,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &CommandInstruction::new(command, nonce).pack(),
+        accounts,
+    )
+}
+
+/// Create the ledger account of `user` and its vault, paid for by `user`.
+pub fn initialize(program_id: &Pubkey, user: &Pubkey) -> Instruction {
+    let (ledger, _) = find_ledger_address(program_id, user);
+    let (vault, _) = find_vault_address(program_id, &ledger);
+    command_instruction(
+        program_id,
+        Command::Initialize,
+        0,
+        initialize_accounts(user, &ledger, &vault),
+    )
+}
+
+/// Accounts of `initialize` and `initialize_with_history`.
+pub(crate) fn initialize_accounts(
+    user: &Pubkey,
+    ledger: &Pubkey,
+    vault: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*user, true),
+        AccountMeta::new(*ledger, false),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ]
+}
+
+/// Create the ledger account of `user` with a history of its last `capacity` operations,
+/// and its vault, paid for by `user`.
+pub fn initialize_with_history(program_id: &Pubkey, user: &Pubkey, capacity: u8) -> Instruction {
+    let mut instruction = initialize(program_id, user);
+    instruction.data =
+        CommandInstruction::new(Command::InitializeWithHistory { capacity }, 0).pack();
+    instruction
+}
+
+/// Deposit `amount` lamports from `depositor` into `ledger`.
+pub fn deposit(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    depositor: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, ledger);
+    let (config, _) = find_config_address(program_id);
+    command_instruction(
+        program_id,
+        Command::Deposit { amount },
+        0,
+        deposit_accounts(ledger, &vault, depositor, &config),
+    )
+}
+
+/// Accounts of `deposit`, and of `deposit_locked` before the authority.
+pub(crate) fn deposit_accounts(
+    ledger: &Pubkey,
+    vault: &Pubkey,
+    depositor: &Pubkey,
+    config: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*ledger, false),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new(*depositor, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*config, false),
+    ]
+}
+
+/// Withdraw `amount` lamports from `ledger` to its `authority`, or to its delegate
+/// passed as `authority`.
+pub fn withdraw(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    authority: &Pubkey,
+    amount: u64,
+    nonce: u64,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, ledger);
+    let (config, _) = find_config_address(program_id);
+    let (fee_vault, _) = find_fee_vault_address(program_id);
+    command_instruction(
+        program_id,
+        Command::Withdraw { amount },
+        nonce,
+        withdraw_accounts(ledger, &vault, authority, &config, &fee_vault),
+    )
+}
+
+/// Accounts of `withdraw`.
+pub(crate) fn withdraw_accounts(
+    ledger: &Pubkey,
+    vault: &Pubkey,
+    authority: &Pubkey,
+    config: &Pubkey,
+    fee_vault: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*ledger, false),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new(*authority, true),
+        AccountMeta::new_readonly(*config, false),
+        // Receives the withdrawal fee
+        AccountMeta::new(*fee_vault, false),
+    ]
+}
+
+/// Return the balance of `ledger`, decoded from the return data with `unpack_balance`.
+///
+/// With the `client` feature, `client::simulate_balance` reads it without sending a
+/// transaction.
+pub fn check_balance(program_id: &Pubkey, ledger: &Pubkey) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::CheckBalance,
+        0,
+        vec![AccountMeta::new_readonly(*ledger, false)],
+    )
+}
+
+/// Decode the balance returned by a `check_balance` instruction.
+///
+/// The runtime may drop trailing zero bytes of return data, so shorter data is
+/// zero-extended, and a transaction without return data has a balance of zero.
+pub fn unpack_balance(return_data: &[u8]) -> Option<u64> {
+    if return_data.len() > 8 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    bytes[..return_data.len()].copy_from_slice(return_data);
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Deposit `amount` tokens of `mint` from the `source` token account of `depositor`.
+///
+/// `depositor` also pays for the token vault the first time `mint` is deposited.
+pub fn token_deposit(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    mint: &Pubkey,
+    depositor: &Pubkey,
+    source: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, ledger);
+    let (token_vault, _) = find_token_vault_address(program_id, ledger, mint);
+    let (config, _) = find_config_address(program_id);
+    command_instruction(
+        program_id,
+        Command::TokenDeposit { amount },
+        0,
+        token_deposit_accounts(
+            ledger,
+            &vault,
+            &token_vault,
+            mint,
+            depositor,
+            source,
+            &config,
+        ),
+    )
+}
+
+/// Accounts of `token_deposit`.
+pub(crate) fn token_deposit_accounts(
+    ledger: &Pubkey,
+    vault: &Pubkey,
+    token_vault: &Pubkey,
+    mint: &Pubkey,
+    depositor: &Pubkey,
+    source: &Pubkey,
+    config: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*ledger, false),
+        AccountMeta::new_readonly(*vault, false),
+        AccountMeta::new(*token_vault, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new(*depositor, true),
+        AccountMeta::new(*source, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*config, false),
+    ]
+}
+
+/// Withdraw `amount` tokens of `mint` from `ledger` to the `destination` token account.
+pub fn token_withdraw(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    mint: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+    amount: u64,
+    nonce: u64,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, ledger);
+    let (token_vault, _) = find_token_vault_address(program_id, ledger, mint);
+    let (config, _) = find_config_address(program_id);
+    command_instruction(
+        program_id,
+        Command::TokenWithdraw { amount },
+        nonce,
+        token_withdraw_accounts(
+            ledger,
+            &vault,
+            &token_vault,
+            mint,
+            authority,
+            destination,
+            &config,
+        ),
+    )
+}
+
+/// Accounts of `token_withdraw`.
+pub(crate) fn token_withdraw_accounts(
+    ledger: &Pubkey,
+    vault: &Pubkey,
+    token_vault: &Pubkey,
+    mint: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+    config: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*ledger, false),
+        AccountMeta::new_readonly(*vault, false),
+        AccountMeta::new(*token_vault, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(*authority, true),
+        AccountMeta::new(*destination, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(*config, false),
+    ]
+}
+
+/// Transfer `amount` lamports from the `source` ledger to the `destination` ledger.
+///
+/// `nonce` is the sequence number of `source`.
+pub fn transfer(
+    program_id: &Pubkey,
+    source: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+    amount: u64,
+    nonce: u64,
+) -> Instruction {
+    let (source_vault, _) = find_vault_address(program_id, source);
+    let (destination_vault, _) = find_vault_address(program_id, destination);
+    let (config, _) = find_config_address(program_id);
+    command_instruction(
+        program_id,
+        Command::Transfer { amount },
+        nonce,
+        transfer_accounts(
+            source,
+            &source_vault,
+            authority,
+            destination,
+            &destination_vault,
+            &config,
+        ),
+    )
+}
+
+/// Accounts of `transfer`.
+pub(crate) fn transfer_accounts(
+    source: &Pubkey,
+    source_vault: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+    destination_vault: &Pubkey,
+    config: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*source, false),
+        AccountMeta::new(*source_vault, false),
+        AccountMeta::new_readonly(*authority, true),
+        AccountMeta::new(*destination, false),
+        AccountMeta::new(*destination_vault, false),
+        AccountMeta::new_readonly(*config, false),
+    ]
+}
+
+/// Migrate the version 1 `ledger` account to the current layout.
+///
+/// The `ledger` keypair must sign, and `authority` becomes the authority of the
+/// migrated account and pays for its vault.
+pub fn migrate(program_id: &Pubkey, ledger: &Pubkey, authority: &Pubkey) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, ledger);
+    command_instruction(
+        program_id,
+        Command::Migrate,
+        0,
+        migrate_accounts(ledger, authority, &vault),
+    )
+}
+
+/// Accounts of `migrate`, and of `upgrade` once the ledger no longer signs.
+pub(crate) fn migrate_accounts(
+    ledger: &Pubkey,
+    authority: &Pubkey,
+    vault: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*ledger, true),
+        AccountMeta::new(*authority, true),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ]
+}
+
+/// Close `ledger`, sweeping its balance to `authority` and its rent to `destination`.
+///
+/// `mints` are the mints ever deposited into `ledger`, in the order of `Data::tokens`,
+/// whose token vaults are closed as well.
+pub fn close(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+    mints: &[Pubkey],
+    nonce: u64,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, ledger);
+    let (config, _) = find_config_address(program_id);
+    let (fee_vault, _) = find_fee_vault_address(program_id);
+    let mut accounts = close_accounts(ledger, &vault, authority, destination, &config, &fee_vault);
+    if !mints.is_empty() {
+        accounts.push(AccountMeta::new_readonly(spl_token::id(), false));
+        accounts.extend(mints.iter().map(|mint| {
+            AccountMeta::new(find_token_vault_address(program_id, ledger, mint).0, false)
+        }));
+    }
+    command_instruction(program_id, Command::Close, nonce, accounts)
+}
+
+/// Accounts of `close` before the token program and token vaults.
+pub(crate) fn close_accounts(
+    ledger: &Pubkey,
+    vault: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+    config: &Pubkey,
+    fee_vault: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*ledger, false),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new(*authority, true),
+        AccountMeta::new(*destination, false),
+        AccountMeta::new_readonly(*config, false),
+        AccountMeta::new(*fee_vault, false),
+    ]
+}
+
+/// Migrate `ledger`, created by `initialize` with an older layout, to the current layout.
+///
+/// Unlike `migrate` the ledger does not sign, and `payer` only pays for the larger account.
+pub fn upgrade(program_id: &Pubkey, ledger: &Pubkey, payer: &Pubkey) -> Instruction {
+    let mut instruction = migrate(program_id, ledger, payer);
+    instruction.accounts[0].is_signer = false;
+    instruction
+}
+
+/// Let `delegate` withdraw up to `amount` from `ledger`.
+pub fn approve(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    authority: &Pubkey,
+    delegate: &Pubkey,
+    amount: u64,
+    nonce: u64,
+) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::Approve {
+            delegate: *delegate,
+            amount,
+        },
+        nonce,
+        vec![
+            AccountMeta::new(*ledger, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+/// Remove the delegate of `ledger`.
+pub fn revoke(program_id: &Pubkey, ledger: &Pubkey, authority: &Pubkey, nonce: u64) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::Revoke,
+        nonce,
+        vec![
+            AccountMeta::new(*ledger, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+/// Create the multisig account `multisig`, a new keypair, requiring `threshold` of
+/// `signers` for the ledgers it governs.
+pub fn create_multisig(
+    program_id: &Pubkey,
+    multisig: &Pubkey,
+    payer: &Pubkey,
+    signers: &[Pubkey],
+    threshold: u8,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*multisig, true),
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    accounts.extend(
+        signers
+            .iter()
+            .map(|signer| AccountMeta::new_readonly(*signer, false)),
+    );
+    command_instruction(
+        program_id,
+        Command::CreateMultisig { threshold },
+        0,
+        accounts,
+    )
+}
+
+/// Hand `ledger` over from `authority` to `new_authority`.
+pub fn set_authority(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    authority: &Pubkey,
+    new_authority: &Pubkey,
+    nonce: u64,
+) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::SetAuthority {
+            authority: *new_authority,
+        },
+        nonce,
+        vec![
+            AccountMeta::new(*ledger, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+/// Withdraw `amount` lamports from `ledger` to `destination`, with `multisig` as its
+/// authority or delegate.
+pub fn multisig_withdraw(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    multisig: &Pubkey,
+    destination: &Pubkey,
+    signers: &[Pubkey],
+    amount: u64,
+    nonce: u64,
+) -> Instruction {
+    let mut instruction = withdraw(program_id, ledger, multisig, amount, nonce);
+    instruction
+        .accounts
+        .push(AccountMeta::new(*destination, false));
+    with_multisig_signers(instruction, multisig, signers)
+}
+
+/// Authorize an instruction built for a single `authority` by the members of the multisig
+/// `authority` instead: the multisig no longer signs and `signers` are appended.
+pub fn with_multisig_signers(
+    mut instruction: Instruction,
+    authority: &Pubkey,
+    signers: &[Pubkey],
+) -> Instruction {
+    for account in &mut instruction.accounts {
+        if account.pubkey == *authority {
+            account.is_signer = false;
+        }
+    }
+    instruction.accounts.extend(
+        signers
+            .iter()
+            .map(|signer| AccountMeta::new_readonly(*signer, true)),
+    );
+    instruction
+}
+
+/// Deposit `amount` lamports from `depositor` into `ledger`, locked until `vesting`
+/// releases them. The `authority` of the ledger must sign as well.
+pub fn deposit_locked(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    depositor: &Pubkey,
+    authority: &Pubkey,
+    amount: u64,
+    vesting: VestingSchedule,
+    nonce: u64,
+) -> Instruction {
+    let mut instruction = deposit(program_id, ledger, depositor, amount);
+    instruction.data =
+        CommandInstruction::new(Command::DepositLocked { amount, vesting }, nonce).pack();
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(*authority, true));
+    instruction
+}
+
+/// Replace the withdrawal limit of `ledger` with `limit`, after `LIMIT_CHANGE_DELAY` unless
+/// `limit` is within the current limit.
+pub fn set_withdrawal_limit(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    authority: &Pubkey,
+    limit: WithdrawalLimit,
+    nonce: u64,
+) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::SetWithdrawalLimit { limit },
+        nonce,
+        vec![
+            AccountMeta::new(*ledger, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+/// Create the config account with `admin`, the upgrade authority of the program, as admin
+/// and treasury, and the fee vault.
+pub fn initialize_config(program_id: &Pubkey, admin: &Pubkey) -> Instruction {
+    let (config, _) = find_config_address(program_id);
+    let program_data = bpf_loader_upgradeable::get_program_data_address(program_id);
+    let (fee_vault, _) = find_fee_vault_address(program_id);
+    command_instruction(
+        program_id,
+        Command::InitializeConfig,
+        0,
+        initialize_config_accounts(admin, &config, &program_data, &fee_vault),
+    )
+}
+
+/// Accounts of `initialize_config`.
+pub(crate) fn initialize_config_accounts(
+    admin: &Pubkey,
+    config: &Pubkey,
+    program_data: &Pubkey,
+    fee_vault: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new(*config, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*program_data, false),
+        AccountMeta::new(*fee_vault, false),
+    ]
+}
+
+/// Replace the pause switch, withdrawal fee and treasury of the config. A fee increase
+/// takes effect after `FEE_CHANGE_DELAY`.
+pub fn update_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    paused: bool,
+    fee_bps: u16,
+    treasury: &Pubkey,
+) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::UpdateConfig {
+            paused,
+            fee_bps,
+            treasury: *treasury,
+        },
+        0,
+        update_config_accounts(&find_config_address(program_id).0, admin),
+    )
+}
+
+/// Accounts of `update_config` and `set_admin`.
+pub(crate) fn update_config_accounts(config: &Pubkey, admin: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*config, false),
+        AccountMeta::new_readonly(*admin, true),
+    ]
+}
+
+/// Hand the admin role of the config over from `admin` to `new_admin`.
+pub fn set_admin(program_id: &Pubkey, admin: &Pubkey, new_admin: &Pubkey) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::SetAdmin { admin: *new_admin },
+        0,
+        update_config_accounts(&find_config_address(program_id).0, admin),
+    )
+}
+
+/// Send the collected withdrawal fees to `treasury`, the treasury of the config.
+pub fn collect_fees(program_id: &Pubkey, treasury: &Pubkey) -> Instruction {
+    command_instruction(
+        program_id,
+        Command::CollectFees,
+        0,
+        collect_fees_accounts(
+            &find_config_address(program_id).0,
+            &find_fee_vault_address(program_id).0,
+            treasury,
+        ),
+    )
+}
+
+/// Accounts of `collect_fees`.
+pub(crate) fn collect_fees_accounts(
+    config: &Pubkey,
+    fee_vault: &Pubkey,
+    treasury: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new_readonly(*config, false),
+        AccountMeta::new(*fee_vault, false),
+        AccountMeta::new(*treasury, false),
+    ]
+}
+
+/// Move `terms.amount` lamports from `ledger` into the new `escrow` account, whose rent
+/// `payer` pays until the escrow is released or refunded.
+///
+/// `escrow` must sign, as a fresh keypair.
+pub fn create_escrow(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    authority: &Pubkey,
+    escrow: &Pubkey,
+    payer: &Pubkey,
+    terms: EscrowTerms,
+    nonce: u64,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, ledger);
+    let (config, _) = find_config_address(program_id);
+    command_instruction(
+        program_id,
+        Command::CreateEscrow { terms },
+        nonce,
+        create_escrow_accounts(ledger, &vault, authority, escrow, payer, &config),
+    )
+}
+
+/// Accounts of `create_escrow`.
+pub(crate) fn create_escrow_accounts(
+    ledger: &Pubkey,
+    vault: &Pubkey,
+    authority: &Pubkey,
+    escrow: &Pubkey,
+    payer: &Pubkey,
+    config: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*ledger, false),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new_readonly(*authority, true),
+        AccountMeta::new(*escrow, true),
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*config, false),
+    ]
+}
+
+/// Pay `escrow`, created from the `source` ledger, out to its `recipient` ledger, signed
+/// by `releaser`: the arbiter, or the recipient authority if the terms allow it. `payer`
+/// is the payer of the escrow.
+pub fn release_escrow(
+    program_id: &Pubkey,
+    escrow: &Pubkey,
+    source: &Pubkey,
+    recipient: &Pubkey,
+    payer: &Pubkey,
+    releaser: &Pubkey,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, recipient);
+    let (config, _) = find_config_address(program_id);
+    command_instruction(
+        program_id,
+        Command::ReleaseEscrow,
+        0,
+        release_escrow_accounts(escrow, recipient, &vault, releaser, payer, &config, source),
+    )
+}
+
+/// Accounts of `release_escrow`.
+pub(crate) fn release_escrow_accounts(
+    escrow: &Pubkey,
+    recipient: &Pubkey,
+    recipient_vault: &Pubkey,
+    releaser: &Pubkey,
+    payer: &Pubkey,
+    config: &Pubkey,
+    source: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(*recipient_vault, false),
+        AccountMeta::new_readonly(*releaser, true),
+        AccountMeta::new(*payer, false),
+        AccountMeta::new_readonly(*config, false),
+        AccountMeta::new(*source, false),
+    ]
+}
+
+/// Pay the expired `escrow` back to its `source` ledger. `payer` is the payer of the
+/// escrow.
+pub fn refund_escrow(
+    program_id: &Pubkey,
+    escrow: &Pubkey,
+    source: &Pubkey,
+    payer: &Pubkey,
+) -> Instruction {
+    let (vault, _) = find_vault_address(program_id, source);
+    let (config, _) = find_config_address(program_id);
+    command_instruction(
+        program_id,
+        Command::RefundEscrow,
+        0,
+        refund_escrow_accounts(escrow, source, &vault, payer, &config),
+    )
+}
+
+/// Accounts of `refund_escrow`.
+pub(crate) fn refund_escrow_accounts(
+    escrow: &Pubkey,
+    source: &Pubkey,
+    source_vault: &Pubkey,
+    payer: &Pubkey,
+    config: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new(*source, false),
+        AccountMeta::new(*source_vault, false),
+        AccountMeta::new(*payer, false),
+        AccountMeta::new_readonly(*config, false),
+    ]
+}
+
+/// Combine instructions built for the same ledger account into a batch that applies their
+/// commands in order and consumes the single `nonce`.
+///
+/// The nonces of `instructions` are ignored. Fails unless there are from 1 to 255
+/// `instructions`, each carrying a command with the ledger account first. The program
+/// rejects batches with a multisig authority.
+pub fn batch(
+    program_id: &Pubkey,
+    instructions: &[Instruction],
+    nonce: u64,
+) -> Result<Instruction, BankError> {
+    let first = instructions.first().ok_or(BankError::InvalidInstruction)?;
+    let mut accounts = first
+        .accounts
+        .get(..1)
+        .ok_or(BankError::InvalidInstruction)?
+        .to_vec();
+    let mut commands = Vec::with_capacity(instructions.len());
+    for instruction in instructions {
+        commands.push(CommandInstruction::unpack(&instruction.data)?.command);
+        // Each command reads its own accounts after the shared ledger account
+        accounts.extend_from_slice(
+            instruction
+                .accounts
+                .get(1..)
+                .ok_or(BankError::InvalidInstruction)?,
+        );
+    }
+    Ok(Instruction::new_with_bytes(
+        *program_id,
+        &BatchInstruction::new(commands, nonce).pack()?,
+        accounts,
+    ))
+}
@@ -1,336 +1,6594 @@
-use bincode::{deserialize, serialize};
+use crate::{error::BankError, events::Event};
+use bincode::{deserialize, serialize_into};
 use serde::{Deserialize, Serialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    clock::Clock,
     entrypoint::ProgramResult,
     msg,
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
+    program_pack::Pack,
     pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
 };
+use spl_token::state::{Account as TokenAccount, Mint};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[cfg(feature = "client")]
+pub mod client;
+pub mod cpi;
+#[cfg(all(feature = "entrypoint", not(feature = "no-entrypoint")))]
+mod entrypoint;
+pub mod error;
+pub mod events;
+pub mod instruction;
+
+/// Seed prefix of the per-user ledger account address.
+pub const LEDGER_SEED: &[u8] = b"ledger";
+
+/// Seed prefix of the vault holding the lamports of a ledger account.
+pub const VAULT_SEED: &[u8] = b"vault";
+
+/// Seed prefix of the token account holding the tokens of one mint for a ledger account.
+pub const TOKEN_VAULT_SEED: &[u8] = b"token_vault";
+
+/// First bytes of every ledger account, identifying the account type.
+pub const ACCOUNT_DISCRIMINATOR: [u8; 8] = *b"bankldgr";
+
+/// Seed of the global config account address.
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Seed of the fee vault address.
+pub const FEE_VAULT_SEED: &[u8] = b"fee_vault";
+
+/// First bytes of the config account.
+pub const CONFIG_DISCRIMINATOR: [u8; 8] = *b"bankconf";
+
+/// Highest withdrawal fee, in basis points.
+pub const MAX_FEE_BPS: u16 = 1_000;
+
+/// Seconds between a withdrawal fee increase and the moment it takes effect.
+pub const FEE_CHANGE_DELAY: i64 = 24 * 60 * 60;
+
+/// First bytes of every multisig account.
+pub const MULTISIG_DISCRIMINATOR: [u8; 8] = *b"bankmsig";
+
+/// Maximum number of members of a multisig account.
+pub const MAX_SIGNERS: usize = 11;
+
+/// First bytes of every escrow account.
+pub const ESCROW_DISCRIMINATOR: [u8; 8] = *b"bankescr";
+
+/// Most entries of the history of a ledger account.
+pub const MAX_HISTORY_LEN: u8 = 64;
+
+/// Seconds between a withdrawal limit change and the moment it takes effect.
+pub const LIMIT_CHANGE_DELAY: i64 = 24 * 60 * 60;
+
+/// Layout version written after the discriminator.
+///
+/// Version 1 is the original headerless `{ number, balance }` layout, see `LegacyData`.
+/// Version 2 holds `number`, `balance`, `authority`, `vault_bump` and `tokens`, and each
+/// later version adds fields to it, see `LAYOUT_CHANGES`.
+pub const LAYOUT_VERSION: u8 = 7;
+
+/// Fields added to `Data` by each layout version from 3 on, as the offset after the
+/// header they are inserted at and their size.
+///
+/// Added fields are all zero until set, so `Data::load_older` reads an older layout by
+/// inserting zeros for each version after its own.
+pub const LAYOUT_CHANGES: [(usize, usize); LAYOUT_VERSION as usize - 2] = [
+    // Version 3: `delegate` and `delegated_amount`, after `vault_bump`
+    (8 + 8 + 32 + 1, 32 + 8),
+    // Version 4: `locked_amount` and `vesting`
+    (8 + 8 + 32 + 1 + 32 + 8, 8 + VestingSchedule::LEN),
+    // Version 5: `withdrawal_limit` up to `window_withdrawn`
+    (
+        8 + 8 + 32 + 1 + 32 + 8 + 8 + VestingSchedule::LEN,
+        2 * WithdrawalLimit::LEN + 8 + 8 + 8,
+    ),
+    // Version 6: `history`
+    (
+        8 + 8 + 32 + 1 + 32 + 8 + 8 + VestingSchedule::LEN + 2 * WithdrawalLimit::LEN + 8 + 8 + 8,
+        History::LEN,
+    ),
+    // Version 7: `open_escrows`, before `history`
+    (
+        8 + 8 + 32 + 1 + 32 + 8 + 8 + VestingSchedule::LEN + 2 * WithdrawalLimit::LEN + 8 + 8 + 8,
+        8,
+    ),
+];
+
+/// Ledger account state, stored after an `ACCOUNT_DISCRIMINATOR` and `LAYOUT_VERSION`
+/// header. Use `Data::load` and `Data::store` to read and write account data.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Data {
+    /// Sequence number, incremented by every command the authority or delegate signs
+    pub number: u64,
+    pub balance: u64,
+    /// Key that must sign every command that takes funds out of the account
+    pub authority: Pubkey,
+    /// Bump seed of the vault derived with `find_vault_address`
+    pub vault_bump: u8,
+    /// Key allowed to withdraw up to `delegated_amount`, `Pubkey::default()` if none
+    pub delegate: Pubkey,
+    /// Remaining allowance of `delegate`
+    pub delegated_amount: u64,
+    /// Lamports deposited with `Command::DepositLocked`, released by `vesting`
+    pub locked_amount: u64,
+    pub vesting: VestingSchedule,
+    /// Cap on the lamports taken out per window
+    pub withdrawal_limit: WithdrawalLimit,
+    /// Limit replacing `withdrawal_limit` at `pending_limit_at`, unless that is zero
+    pub pending_limit: WithdrawalLimit,
+    pub pending_limit_at: i64,
+    /// Time `window_withdrawn` was last drained
+    pub window_start: i64,
+    /// Lamports counted against `withdrawal_limit`, drained over its window
+    pub window_withdrawn: u64,
+    /// Escrows created from this account and not yet released or refunded. Escrows
+    /// created before layout version 7 are not counted, see `Escrow::counted`.
+    pub open_escrows: u64,
+    /// Last operations on the balances, if enabled at initialization
+    pub history: History,
+    /// SPL token balances, one entry per mint ever deposited
+    pub tokens: Vec<TokenBalance>,
+}
+
+impl Data {
+    /// Size of the account header (discriminator + version).
+    pub const HEADER_LEN: usize = 8 + 1;
+
+    /// Size of the account data without token balances or history entries
+    /// (header + u64 + u64 + Pubkey + u8 + Pubkey + u64 + u64 + VestingSchedule
+    /// + 2 WithdrawalLimit + i64 + i64 + u64 + u64 + History + Vec length).
+    pub const LEN: usize = Self::HEADER_LEN
+        + 8
+        + 8
+        + 32
+        + 1
+        + 32
+        + 8
+        + 8
+        + VestingSchedule::LEN
+        + 2 * WithdrawalLimit::LEN
+        + 8
+        + 8
+        + 8
+        + 8
+        + History::LEN
+        + 8;
+
+    /// Empty ledger state owned by `authority`.
+    pub fn new(authority: Pubkey, vault_bump: u8) -> Self {
+        Data {
+            number: 0,
+            balance: 0,
+            authority,
+            vault_bump,
+            delegate: Pubkey::default(),
+            delegated_amount: 0,
+            locked_amount: 0,
+            vesting: VestingSchedule::default(),
+            withdrawal_limit: WithdrawalLimit::default(),
+            pending_limit: WithdrawalLimit::default(),
+            pending_limit_at: 0,
+            window_start: 0,
+            window_withdrawn: 0,
+            open_escrows: 0,
+            history: History::default(),
+            tokens: vec![],
+        }
+    }
+
+    /// Size of the account data, with room for a full history.
+    pub fn size(&self) -> usize {
+        Self::LEN
+            + self.tokens.len() * TokenBalance::LEN
+            + usize::from(self.history.capacity) * HistoryEntry::LEN
+    }
+
+    /// Layout version of ledger account data.
+    pub fn version(bytes: &[u8]) -> Result<u8, BankError> {
+        if bytes.len() >= Self::HEADER_LEN && bytes[..8] == ACCOUNT_DISCRIMINATOR {
+            Ok(bytes[8])
+        } else if bytes.len() == LegacyData::LEN {
+            Ok(1)
+        } else {
+            Err(BankError::InvalidAccountData)
+        }
+    }
+
+    /// Read ledger account data written with the current layout.
+    pub fn load(bytes: &[u8]) -> Result<Self, BankError> {
+        match Self::version(bytes)? {
+            LAYOUT_VERSION => {
+                deserialize(&bytes[Self::HEADER_LEN..]).map_err(|_| BankError::InvalidAccountData)
+            }
+            version if version < LAYOUT_VERSION => Err(BankError::AccountNeedsMigration),
+            _ => Err(BankError::UnsupportedAccountVersion),
+        }
+    }
+
+    /// Read ledger account data written with a layout from version 2 up to the current
+    /// one, filling the fields added since with zeros.
+    pub fn load_older(bytes: &[u8]) -> Result<Self, BankError> {
+        let version = Self::version(bytes)?;
+        if !(2..=LAYOUT_VERSION).contains(&version) {
+            return Err(BankError::UnsupportedAccountVersion);
+        }
+        let mut data = bytes[Self::HEADER_LEN..].to_vec();
+        for &(offset, len) in &LAYOUT_CHANGES[usize::from(version) - 2..] {
+            if offset > data.len() {
+                return Err(BankError::InvalidAccountData);
+            }
+            data.splice(offset..offset, std::iter::repeat_n(0, len));
+        }
+        deserialize(&data).map_err(|_| BankError::InvalidAccountData)
+    }
+
+    /// Write the header and the data with the current layout.
+    pub fn store(&self, bytes: &mut [u8]) -> ProgramResult {
+        if bytes.len() < self.size() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        bytes[..8].copy_from_slice(&ACCOUNT_DISCRIMINATOR);
+        bytes[8] = LAYOUT_VERSION;
+        serialize_into(&mut bytes[Self::HEADER_LEN..], self)
+            .map_err(|_| ProgramError::AccountDataTooSmall)
+    }
+
+    /// Balance of `mint` tokens held for this account.
+    pub fn token_balance(&self, mint: &Pubkey) -> u64 {
+        self.tokens
+            .iter()
+            .find(|token| token.mint == *mint)
+            .map_or(0, |token| token.amount)
+    }
+
+    /// Add `amount` to the SOL balance.
+    pub fn credit(&mut self, amount: u64) -> Result<(), BankError> {
+        self.balance = self
+            .balance
+            .checked_add(amount)
+            .ok_or(BankError::Overflow)?;
+        Ok(())
+    }
+
+    /// Remove `amount` from the SOL balance.
+    pub fn debit(&mut self, amount: u64) -> Result<(), BankError> {
+        self.balance = self
+            .balance
+            .checked_sub(amount)
+            .ok_or(BankError::InsufficientFunds)?;
+        Ok(())
+    }
+
+    /// Part of the balance still locked by the vesting schedule at `now`.
+    pub fn locked(&self, now: i64) -> u64 {
+        self.locked_amount - self.vesting.vested(self.locked_amount, now)
+    }
+
+    /// Remove `amount` from the SOL balance, keeping what is still locked at `now`.
+    pub fn debit_unlocked(&mut self, amount: u64, now: i64) -> Result<(), BankError> {
+        self.debit(amount)?;
+        if self.balance < self.locked(now) {
+            return Err(BankError::FundsLocked);
+        }
+        Ok(())
+    }
+
+    /// Lock `amount` of the balance until `vesting` releases it.
+    ///
+    /// The amount joins the active lock if it has the same schedule, and replaces a lock
+    /// that was fully released.
+    pub fn lock(
+        &mut self,
+        amount: u64,
+        vesting: VestingSchedule,
+        now: i64,
+    ) -> Result<(), BankError> {
+        if !vesting.is_valid() {
+            return Err(BankError::InvalidVestingSchedule);
+        }
+        if self.locked(now) == 0 {
+            self.locked_amount = amount;
+            self.vesting = vesting;
+        } else if self.vesting == vesting {
+            self.locked_amount = self
+                .locked_amount
+                .checked_add(amount)
+                .ok_or(BankError::Overflow)?;
+        } else {
+            return Err(BankError::InvalidVestingSchedule);
+        }
+        Ok(())
+    }
+
+    /// Replace the withdrawal limit with `limit`, dropping any change still pending. A limit
+    /// within the one in force applies at once, any other `LIMIT_CHANGE_DELAY` seconds after
+    /// `now`. Returns when the change takes effect.
+    pub fn schedule_limit(&mut self, limit: WithdrawalLimit, now: i64) -> Result<i64, BankError> {
+        if !limit.is_valid() {
+            return Err(BankError::InvalidWithdrawalLimit);
+        }
+        self.apply_pending_limit(now);
+        if limit.is_within(&self.withdrawal_limit) {
+            self.withdrawal_limit = limit;
+            self.pending_limit = WithdrawalLimit::default();
+            self.pending_limit_at = 0;
+            return Ok(now);
+        }
+        self.pending_limit = limit;
+        self.pending_limit_at = now
+            .checked_add(LIMIT_CHANGE_DELAY)
+            .ok_or(BankError::Overflow)?;
+        Ok(self.pending_limit_at)
+    }
+
+    /// Count `amount` taken out at `now` against the withdrawal limit in force.
+    pub fn record_withdrawal(&mut self, amount: u64, now: i64) -> Result<(), BankError> {
+        self.apply_pending_limit(now);
+        let limit = self.withdrawal_limit;
+        if limit.amount == 0 {
+            return Ok(());
+        }
+        // Drain `limit.amount` per window since the last drain. Time is only consumed once
+        // a lamport drained, so frequent withdrawals can't round the drain down to nothing.
+        let elapsed = (i128::from(now) - i128::from(self.window_start)).max(0) as u128;
+        let drained = u128::from(limit.amount) * elapsed / limit.window as u128;
+        if drained > 0 || self.window_withdrawn == 0 {
+            self.window_withdrawn = self
+                .window_withdrawn
+                .saturating_sub(drained.try_into().unwrap_or(u64::MAX));
+            self.window_start = now;
+        }
+        self.window_withdrawn = self
+            .window_withdrawn
+            .checked_add(amount)
+            .filter(|&withdrawn| withdrawn <= limit.amount)
+            .ok_or(BankError::WithdrawalLimitExceeded)?;
+        Ok(())
+    }
+
+    fn apply_pending_limit(&mut self, now: i64) {
+        if self.pending_limit_at != 0 && now >= self.pending_limit_at {
+            self.withdrawal_limit = self.pending_limit;
+            self.pending_limit = WithdrawalLimit::default();
+            self.pending_limit_at = 0;
+        }
+    }
+
+    /// Add `amount` to the balance of `mint` tokens, adding an entry for a new mint.
+    pub fn credit_token(&mut self, mint: &Pubkey, amount: u64) -> Result<(), BankError> {
+        match self.tokens.iter_mut().find(|token| token.mint == *mint) {
+            Some(token) => {
+                token.amount = token
+                    .amount
+                    .checked_add(amount)
+                    .ok_or(BankError::Overflow)?;
+            }
+            None => self.tokens.push(TokenBalance {
+                mint: *mint,
+                amount,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Remove `amount` from the balance of `mint` tokens.
+    pub fn debit_token(&mut self, mint: &Pubkey, amount: u64) -> Result<(), BankError> {
+        let token = self
+            .tokens
+            .iter_mut()
+            .find(|token| token.mint == *mint)
+            .ok_or(BankError::InsufficientFunds)?;
+        token.amount = token
+            .amount
+            .checked_sub(amount)
+            .ok_or(BankError::InsufficientFunds)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenBalance {
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+impl TokenBalance {
+    /// Size of a serialized entry (Pubkey + u64).
+    pub const LEN: usize = 32 + 8;
+}
+
+/// Release schedule of locked lamports, as Unix timestamps of the Clock sysvar.
+///
+/// The locked amount vests linearly from `start` to `end`, but nothing is released
+/// before `cliff`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VestingSchedule {
+    pub start: i64,
+    pub cliff: i64,
+    pub end: i64,
+}
+
+impl VestingSchedule {
+    /// Size of a serialized schedule (i64 + i64 + i64).
+    pub const LEN: usize = 8 + 8 + 8;
+
+    /// Release everything at `timestamp`.
+    pub fn unlock_at(timestamp: i64) -> Self {
+        VestingSchedule {
+            start: timestamp,
+            cliff: timestamp,
+            end: timestamp,
+        }
+    }
+
+    /// Whether `start <= cliff <= end`.
+    pub fn is_valid(&self) -> bool {
+        self.start <= self.cliff && self.cliff <= self.end
+    }
+
+    /// Part of `amount` released at `now`.
+    pub fn vested(&self, amount: u64, now: i64) -> u64 {
+        if now < self.cliff {
+            0
+        } else if now >= self.end {
+            amount
+        } else {
+            // cliff <= now < end, so the duration is positive. The differences span up
+            // to 2^64 - 1 seconds, so they are computed in i128 to not overflow.
+            let elapsed = (i128::from(now) - i128::from(self.start)) as u128;
+            let duration = (i128::from(self.end) - i128::from(self.start)) as u128;
+            (u128::from(amount) * elapsed / duration) as u64
+        }
+    }
+}
+
+/// Cap of `amount` lamports taken out of a ledger account per rolling `window` seconds.
+///
+/// Withdrawals are counted until they drain away at `amount` per `window`, so the cap
+/// refills gradually instead of all at once when a fixed window ends. An `amount` of
+/// zero means no limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WithdrawalLimit {
+    pub amount: u64,
+    pub window: i64,
+}
+
+impl WithdrawalLimit {
+    /// Size of a serialized limit (u64 + i64).
+    pub const LEN: usize = 8 + 8;
+
+    /// Whether the limit is disabled or has a positive window.
+    pub fn is_valid(&self) -> bool {
+        self.amount == 0 || self.window > 0
+    }
+
+    /// Whether the limit lets no more out than `other`, neither at once nor over time.
+    pub fn is_within(&self, other: &WithdrawalLimit) -> bool {
+        if other.amount == 0 {
+            return true;
+        }
+        self.amount != 0
+            && self.amount <= other.amount
+            && i128::from(self.amount) * i128::from(other.window)
+                <= i128::from(other.amount) * i128::from(self.window)
+    }
+}
+
+/// Kind of operation recorded in the history of a ledger account.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Lamports deposited, locked or not, by the counterparty
+    Deposit,
+    /// Lamports withdrawn to the counterparty
+    Withdraw,
+    /// Tokens deposited from the counterparty token account
+    TokenDeposit,
+    /// Tokens withdrawn to the counterparty token account
+    TokenWithdraw,
+    /// Lamports transferred to the counterparty ledger account
+    TransferOut,
+    /// Lamports transferred from the counterparty ledger account
+    TransferIn,
+    /// Lamports moved into the counterparty escrow account
+    EscrowOut,
+    /// Lamports released or refunded by the counterparty escrow account
+    EscrowIn,
+}
+
+/// Operation recorded in the history of a ledger account.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub kind: Operation,
+    /// Lamports, or tokens in base units
+    pub amount: u64,
+    /// Slot the operation was processed in
+    pub slot: u64,
+    pub counterparty: Pubkey,
+}
+
+impl HistoryEntry {
+    /// Size of a serialized entry (enum tag + u64 + u64 + Pubkey).
+    pub const LEN: usize = 4 + 8 + 8 + 32;
+}
+
+/// Ring buffer of the last `capacity` operations of a ledger account.
+///
+/// The account is sized for `capacity` entries up front, so recording never grows it.
+/// A `capacity` of zero disables the history.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct History {
+    pub capacity: u8,
+    /// Index of the entry the next operation is written to
+    pub next: u8,
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    /// Size of a serialized history without entries (u8 + u8 + Vec length).
+    pub const LEN: usize = 1 + 1 + 8;
+
+    /// Record `entry`, replacing the oldest entry once the history is full.
+    pub fn record(&mut self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let next = usize::from(self.next);
+        if next < self.entries.len() {
+            self.entries[next] = entry;
+        } else {
+            self.entries.push(entry);
+        }
+        self.next = ((next + 1) % usize::from(self.capacity)) as u8;
+    }
+
+    /// Recorded entries, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &HistoryEntry> {
+        let (newer, older) = self.entries.split_at(usize::from(self.next));
+        older.iter().chain(newer)
+    }
+}
+
+/// Ledger account state of layout version 1, without header, authority or vault.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LegacyData {
     pub number: u64,
     pub balance: u64,
 }
 
+impl LegacyData {
+    /// Size of the account data (u64 + u64).
+    pub const LEN: usize = 8 + 8;
+}
+
+/// Multisig account state, stored after a `MULTISIG_DISCRIMINATOR`.
+///
+/// A ledger whose authority is a multisig account accepts commands signed by at least
+/// `threshold` of `signers` instead of a signature of the authority itself.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Multisig {
+    pub threshold: u8,
+    pub signers: Vec<Pubkey>,
+}
+
+impl Multisig {
+    /// Size of the account data (discriminator + u8 + Vec length + signers).
+    pub fn size(&self) -> usize {
+        8 + 1 + 8 + self.signers.len() * 32
+    }
+
+    /// Read multisig account data.
+    pub fn load(bytes: &[u8]) -> Result<Self, BankError> {
+        if bytes.len() < 8 || bytes[..8] != MULTISIG_DISCRIMINATOR {
+            return Err(BankError::InvalidAccountData);
+        }
+        deserialize(&bytes[8..]).map_err(|_| BankError::InvalidAccountData)
+    }
+
+    /// Write the discriminator and the data.
+    pub fn store(&self, bytes: &mut [u8]) -> ProgramResult {
+        if bytes.len() < self.size() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        bytes[..8].copy_from_slice(&MULTISIG_DISCRIMINATOR);
+        serialize_into(&mut bytes[8..], self).map_err(|_| ProgramError::AccountDataTooSmall)
+    }
+
+    /// Ensure at least `threshold` distinct members are among the signing `signers`.
+    ///
+    /// Accounts of non-members, and members listed more than once, are not counted.
+    pub fn check_signers(&self, signers: &[AccountInfo]) -> ProgramResult {
+        let mut matched = [false; MAX_SIGNERS];
+        for signer in signers.iter().filter(|signer| signer.is_signer) {
+            if let Some(position) = self.signers.iter().position(|key| key == signer.key) {
+                matched[position] = true;
+            }
+        }
+        let count = matched.iter().filter(|&&matched| matched).count();
+        if count < usize::from(self.threshold) {
+            return Err(BankError::NotEnoughSigners.into());
+        }
+        Ok(())
+    }
+}
+
+/// What an escrow pays out and who may release it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EscrowTerms {
+    /// Ledger account receiving the amount on release
+    pub recipient: Pubkey,
+    /// Key that may release the amount to the recipient
+    pub arbiter: Pubkey,
+    /// Lamports held by the escrow
+    pub amount: u64,
+    /// First slot at which the escrow can only be refunded
+    pub expiry_slot: u64,
+    /// Whether the authority of the recipient ledger may release as well
+    pub recipient_can_release: bool,
+}
+
+impl EscrowTerms {
+    /// Size of serialized terms (Pubkey + Pubkey + u64 + u64 + bool).
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 1;
+}
+
+/// Escrow account state, stored after an `ESCROW_DISCRIMINATOR`.
+///
+/// The account holds its rent-exempt minimum plus `terms.amount`, taken from the `source`
+/// ledger account. Before `terms.expiry_slot` the arbiter can release the amount to the
+/// recipient ledger, from then on anyone can refund it to the source. Either way the rent
+/// goes back to `payer` and the account is wiped.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Escrow {
+    /// Ledger account the amount was taken from
+    pub source: Pubkey,
+    /// Account that paid the rent of the escrow account
+    pub payer: Pubkey,
+    pub terms: EscrowTerms,
+    /// Whether the source counts the escrow in `Data::open_escrows`, which it doesn't for
+    /// escrows created before ledger layout version 7
+    pub counted: bool,
+}
+
+impl Escrow {
+    /// Size of the account data of escrows created before ledger layout version 7
+    /// (discriminator + Pubkey + Pubkey + terms).
+    pub const UNCOUNTED_LEN: usize = 8 + 32 + 32 + EscrowTerms::LEN;
+
+    /// Size of the account data (discriminator + Pubkey + Pubkey + terms + bool).
+    pub const LEN: usize = Self::UNCOUNTED_LEN + 1;
+
+    /// Read escrow account data.
+    pub fn load(bytes: &[u8]) -> Result<Self, BankError> {
+        if bytes.len() < Self::UNCOUNTED_LEN || bytes[..8] != ESCROW_DISCRIMINATOR {
+            return Err(BankError::InvalidEscrow);
+        }
+        if bytes.len() == Self::UNCOUNTED_LEN {
+            // Older escrow accounts end before `counted`, a zero byte reads as false
+            let mut bytes = bytes[8..].to_vec();
+            bytes.push(0);
+            return deserialize(&bytes).map_err(|_| BankError::InvalidEscrow);
+        }
+        deserialize(&bytes[8..]).map_err(|_| BankError::InvalidEscrow)
+    }
+
+    /// Write the discriminator and the data.
+    pub fn store(&self, bytes: &mut [u8]) -> ProgramResult {
+        if bytes.len() < Self::LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        bytes[..8].copy_from_slice(&ESCROW_DISCRIMINATOR);
+        serialize_into(&mut bytes[8..], self).map_err(|_| ProgramError::AccountDataTooSmall)
+    }
+}
+
+/// Global settings of the program, stored after a `CONFIG_DISCRIMINATOR` in the account
+/// at `find_config_address`.
+///
+/// Until the admin creates the account, nothing is paused and no fee is charged.
+/// Withdrawal fees are held by the fee vault at `find_fee_vault_address` until
+/// `Command::CollectFees` sends them to the treasury, so only the admin commands write
+/// the config account and the commands reading it run in parallel. Withdrawals and closes
+/// still write-lock the fee vault.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Key that must sign every change to the config
+    pub admin: Pubkey,
+    /// Blocks every command that moves funds in or out of a ledger account
+    pub paused: bool,
+    /// Fee taken from withdrawals, in basis points
+    pub fee_bps: u16,
+    /// Fee replacing `fee_bps` at `pending_fee_at`, unless that is zero
+    pub pending_fee_bps: u16,
+    pub pending_fee_at: i64,
+    /// Receiver of the collected fees
+    pub treasury: Pubkey,
+    /// Bump seed of the config address
+    pub bump: u8,
+}
+
+impl Config {
+    /// Size of the account data (discriminator + Pubkey + bool + u16 + u16 + i64 + Pubkey
+    /// + u8).
+    pub const LEN: usize = 8 + 32 + 1 + 2 + 2 + 8 + 32 + 1;
+
+    /// Read config account data.
+    pub fn load(bytes: &[u8]) -> Result<Self, BankError> {
+        if bytes.len() < Self::LEN || bytes[..8] != CONFIG_DISCRIMINATOR {
+            return Err(BankError::InvalidConfig);
+        }
+        deserialize(&bytes[8..]).map_err(|_| BankError::InvalidConfig)
+    }
+
+    /// Write the discriminator and the data.
+    pub fn store(&self, bytes: &mut [u8]) -> ProgramResult {
+        if bytes.len() < Self::LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        bytes[..8].copy_from_slice(&CONFIG_DISCRIMINATOR);
+        serialize_into(&mut bytes[8..], self).map_err(|_| ProgramError::AccountDataTooSmall)
+    }
+
+    /// Fee in force at `now`, in basis points.
+    pub fn fee_bps_at(&self, now: i64) -> u16 {
+        if self.pending_fee_at != 0 && now >= self.pending_fee_at {
+            self.pending_fee_bps
+        } else {
+            self.fee_bps
+        }
+    }
+
+    /// Replace the withdrawal fee with `fee_bps`, dropping any change still pending. A fee
+    /// no higher than the one in force applies at once, a higher one `FEE_CHANGE_DELAY`
+    /// seconds after `now`. Returns when the change takes effect.
+    pub fn schedule_fee(&mut self, fee_bps: u16, now: i64) -> Result<i64, BankError> {
+        if fee_bps > MAX_FEE_BPS {
+            return Err(BankError::InvalidConfig);
+        }
+        self.fee_bps = self.fee_bps_at(now);
+        self.pending_fee_bps = 0;
+        self.pending_fee_at = 0;
+        if fee_bps <= self.fee_bps {
+            self.fee_bps = fee_bps;
+            return Ok(now);
+        }
+        self.pending_fee_bps = fee_bps;
+        self.pending_fee_at = now
+            .checked_add(FEE_CHANGE_DELAY)
+            .ok_or(BankError::Overflow)?;
+        Ok(self.pending_fee_at)
+    }
+
+    /// Fee charged at `now` on a withdrawal of `amount`, rounded down.
+    pub fn fee(&self, amount: u64, now: i64) -> u64 {
+        (u128::from(amount) * u128::from(self.fee_bps_at(now)) / 10_000) as u64
+    }
+}
+
+/// Derive the address of the global config account.
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Derive the address of the fee vault, created with the config account.
+///
+/// The fee vault is a data-less account owned by the program. It holds the rent-exempt
+/// minimum plus the withdrawal fees not collected yet.
+pub fn find_fee_vault_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[FEE_VAULT_SEED], program_id)
+}
+
+/// Derive the ledger account address of `user` for this program.
+pub fn find_ledger_address(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[LEDGER_SEED, user.as_ref()], program_id)
+}
+
+/// Derive the vault address of a ledger account.
+///
+/// The vault is a data-less account owned by the program. It holds the rent-exempt
+/// minimum plus exactly `Data.balance` lamports.
+pub fn find_vault_address(program_id: &Pubkey, ledger: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VAULT_SEED, ledger.as_ref()], program_id)
+}
+
+/// Derive the address of the token account holding `mint` tokens for a ledger account.
+///
+/// The token account is owned by the ledger's vault, which signs outgoing transfers.
+pub fn find_token_vault_address(
+    program_id: &Pubkey,
+    ledger: &Pubkey,
+    mint: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[TOKEN_VAULT_SEED, ledger.as_ref(), mint.as_ref()],
+        program_id,
+    )
+}
+
 pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
+    if instruction_data.first() == Some(&BatchInstruction::TAG) {
+        return process_batch(
+            program_id,
+            accounts,
+            BatchInstruction::unpack(instruction_data)?,
+        );
+    }
+
+    // Decode the instruction data, or the bincode encoding of older clients
+    let instruction = if LegacyCommandInstruction::matches(instruction_data) {
+        msg!("Legacy instruction encoding");
+        CommandInstruction::unpack_legacy(program_id, instruction_data)?
+    } else {
+        CommandInstruction::unpack(instruction_data)?
+    };
+
+    validate_parameters(instruction.command)?;
+
+    // The account does not hold current ledger data yet, so these have their own account layout
+    if let Command::Initialize
+    | Command::InitializeWithHistory { .. }
+    | Command::Migrate
+    | Command::CreateMultisig { .. } = instruction.command
+    {
+        return match instruction.command {
+            Command::Initialize => process_initialize(program_id, accounts, 0),
+            Command::InitializeWithHistory { capacity } => {
+                process_initialize(program_id, accounts, capacity)
+            }
+            Command::CreateMultisig { threshold } => {
+                process_create_multisig(program_id, accounts, threshold)
+            }
+            _ => process_migrate(program_id, accounts),
+        };
+    }
+    // These act on the config account instead of a ledger account
+    match instruction.command {
+        Command::InitializeConfig => return process_initialize_config(program_id, accounts),
+        Command::UpdateConfig { .. } | Command::SetAdmin { .. } => {
+            return process_update_config(program_id, accounts, instruction.command)
+        }
+        Command::CollectFees => return process_collect_fees(program_id, accounts),
+        _ => {}
+    }
+    // These pay out an escrow account, which names the ledger account receiving the funds
+    match instruction.command {
+        Command::ReleaseEscrow => return process_release_escrow(program_id, accounts),
+        Command::RefundEscrow => return process_refund_escrow(program_id, accounts),
+        _ => {}
+    }
+    // Read-only, so the ledger account need not be writable and is never rewritten
+    if let Command::CheckBalance = instruction.command {
+        return process_check_balance(program_id, accounts);
+    }
+
     let accounts_iter = &mut accounts.iter();
     let account = next_account_info(accounts_iter)?;
+    let mut data = load_ledger(program_id, account)?;
 
-    // Ensure the account is writable
-    if !account.is_writable {
-        return Err(ProgramError::InvalidAccountData);
+    // Every signed command consumes the current sequence number, so a signed
+    // instruction cannot be replayed once it has been processed
+    if instruction.command.needs_nonce() {
+        consume_nonce(&mut data, instruction.nonce)?;
     }
 
-    // Ensure the account is owned by the program
-    if *account.owner != *program_id {
-        return Err(ProgramError::IncorrectProgramId);
+    if let Command::Close = instruction.command {
+        // The account is wiped instead of stored
+        return process_close(program_id, account, &mut data, accounts_iter);
     }
+    process_command(
+        program_id,
+        account,
+        &mut data,
+        accounts_iter,
+        instruction.command,
+    )?;
 
-    // Deserialize the instruction data
-    let instruction: CommandInstruction =
-        deserialize(instruction_data).map_err(|_| ProgramError::InvalidAccountData)?;
+    // Serialize and save the data back to the account
+    data.store(&mut account.data.borrow_mut())?;
 
-    // Validate the instruction parameters
-    match instruction.command {
-        Command::Deposit { amount } => {
+    Ok(())
+}
+
+/// Apply the commands of a batch in order to the same ledger account, loading and storing
+/// its data once. The batch consumes a single sequence number, if any of its commands
+/// needs it.
+///
+/// Multisig accounts are rejected: the members signing for a multisig authority follow
+/// its command's accounts in any number, so the next command couldn't find its own.
+///
+/// Accounts:
+/// 0. `[writable]` Ledger account
+/// 1. The accounts of each command after its ledger account, command after command
+fn process_batch(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    batch: BatchInstruction,
+) -> ProgramResult {
+    // Nothing runs unless every command can
+    for &command in &batch.commands {
+        if !command.is_batchable() {
+            return Err(BankError::InvalidInstruction.into());
+        }
+        validate_parameters(command)?;
+    }
+    if accounts
+        .iter()
+        .any(|account| is_multisig(program_id, account))
+    {
+        return Err(BankError::InvalidInstruction.into());
+    }
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let mut data = load_ledger(program_id, account)?;
+    if batch.commands.iter().any(Command::needs_nonce) {
+        consume_nonce(&mut data, batch.nonce)?;
+    }
+
+    for command in batch.commands {
+        process_command(program_id, account, &mut data, accounts_iter, command)?;
+    }
+    data.store(&mut account.data.borrow_mut())?;
+
+    Ok(())
+}
+
+/// Reject commands whose parameters can never succeed, before any account is read.
+fn validate_parameters(command: Command) -> ProgramResult {
+    match command {
+        Command::Deposit { amount } | Command::DepositLocked { amount, .. } => {
             if amount == 0 {
-                return Err(ProgramError::InvalidInstructionData); // Invalid amount for deposit
+                return Err(BankError::ZeroAmount.into()); // Invalid amount for deposit
             }
         }
         Command::Withdraw { amount } => {
             if amount == 0 {
-                return Err(ProgramError::InvalidInstructionData); // Invalid amount for withdrawal
+                return Err(BankError::ZeroAmount.into()); // Invalid amount for withdrawal
             }
         }
-        Command::CheckBalance => {
-            // No parameters to validate for CheckBalance
+        Command::Transfer { amount } => {
+            if amount == 0 {
+                return Err(BankError::ZeroAmount.into());
+            }
         }
-    }
-
-    // Ensure the account is owned by the instruction program
-    if *account.owner != instruction.program_id {
-        return Err(ProgramError::IncorrectProgramId);
-    }
-    // Read existing data or initialize if empty
-    let mut data = if account.data.borrow().len() == 0 {
-        Data {
-            number: 0,
-            balance: 0,
+        Command::TokenDeposit { amount } | Command::TokenWithdraw { amount } => {
+            if amount == 0 {
+                return Err(BankError::ZeroAmount.into());
+            }
         }
-    } else {
-        deserialize(&account.data.borrow()).map_err(|_| ProgramError::InvalidAccountData)?
-    };
-
-    match instruction.command {
-        Command::Deposit { amount } => {
-            data.balance += amount;
-            msg!("Deposited {} SOL. New balance: {}", amount, data.balance);
+        Command::Approve { amount, .. } => {
+            if amount == 0 {
+                return Err(BankError::ZeroAmount.into()); // Revoke removes a delegate
+            }
         }
-        Command::Withdraw { amount } => {
-            if amount > data.balance {
-                return Err(ProgramError::InsufficientFunds);
+        Command::CreateEscrow { terms } => {
+            if terms.amount == 0 {
+                return Err(BankError::ZeroAmount.into());
             }
-            data.balance -= amount;
-            msg!("Withdrew {} SOL. New balance: {}", amount, data.balance);
         }
-        Command::CheckBalance => {
-            msg!("Current balance: {}", data.balance);
+        Command::InitializeWithHistory { capacity } => {
+            if capacity == 0 || capacity > MAX_HISTORY_LEN {
+                return Err(BankError::InvalidHistoryLength.into());
+            }
+        }
+        Command::CheckBalance
+        | Command::Initialize
+        | Command::Migrate
+        | Command::Close
+        | Command::Revoke
+        | Command::CreateMultisig { .. }
+        | Command::SetAuthority { .. }
+        | Command::InitializeConfig
+        | Command::UpdateConfig { .. }
+        | Command::SetAdmin { .. }
+        | Command::CollectFees
+        | Command::ReleaseEscrow
+        | Command::RefundEscrow => {
+            // No parameters to validate
+        }
+        Command::SetWithdrawalLimit { limit } => {
+            if !limit.is_valid() {
+                return Err(BankError::InvalidWithdrawalLimit.into());
+            }
         }
     }
+    Ok(())
+}
 
-    // Serialize and save the data back to the account
-    let serialized_data = serialize(&data).map_err(|_| ProgramError::InvalidAccountData)?;
-    account.data.borrow_mut().copy_from_slice(&serialized_data);
+/// Read the data of the writable ledger `account`.
+fn load_ledger(program_id: &Pubkey, account: &AccountInfo) -> Result<Data, ProgramError> {
+    // Ensure the account is writable
+    if !account.is_writable {
+        return Err(BankError::AccountNotWritable.into());
+    }
+
+    // Ensure the account is owned by the program
+    if *account.owner != *program_id {
+        return Err(BankError::InvalidAccountOwner.into());
+    }
+
+    // Accounts must be created with Command::Initialize first
+    if account.data_is_empty() {
+        return Err(BankError::UninitializedAccount.into());
+    }
+    Ok(Data::load(&account.data.borrow())?)
+}
+
+/// Ensure `nonce` is the sequence number of the ledger, and advance it.
+fn consume_nonce(data: &mut Data, nonce: u64) -> Result<(), BankError> {
+    if nonce != data.number {
+        return Err(BankError::InvalidNonce);
+    }
+    data.number = data.number.checked_add(1).ok_or(BankError::Overflow)?;
+    Ok(())
+}
+
+/// Record an operation on the balances of `data` with `counterparty` in its history.
+fn record_operation(
+    data: &mut Data,
+    kind: Operation,
+    amount: u64,
+    counterparty: &Pubkey,
+) -> ProgramResult {
+    // Without a history there is nothing to record, so skip the clock syscall
+    if data.history.capacity == 0 {
+        return Ok(());
+    }
+    data.history.record(HistoryEntry {
+        kind,
+        amount,
+        slot: Clock::get()?.slot,
+        counterparty: *counterparty,
+    });
+    Ok(())
+}
+
+/// Apply a command that changes the ledger `account` to its `data`, which the caller stores.
+fn process_command<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    data: &mut Data,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+    command: Command,
+) -> ProgramResult {
+    match command {
+        Command::Deposit { amount } | Command::DepositLocked { amount, .. } => {
+            let vault = next_account_info(accounts_iter)?;
+            let depositor = next_account_info(accounts_iter)?;
+            let system_program_account = next_account_info(accounts_iter)?;
+            let config = next_account_info(accounts_iter)?;
+            check_vault(program_id, account, data, vault)?;
+            load_unpaused_config(program_id, config)?;
+            // Only the authority may lock its own funds, anyone else could block its withdrawals
+            if let Command::DepositLocked { .. } = command {
+                let authority = next_account_info(accounts_iter)?;
+                check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+            }
+            data.credit(amount)?;
+
+            // Move the lamports into the vault
+            invoke(
+                &system_instruction::transfer(depositor.key, vault.key, amount),
+                &[
+                    depositor.clone(),
+                    vault.clone(),
+                    system_program_account.clone(),
+                ],
+            )?;
+            record_operation(data, Operation::Deposit, amount, depositor.key)?;
+            Event::Deposited {
+                ledger: *account.key,
+                amount,
+                balance: data.balance,
+            }
+            .emit();
+
+            if let Command::DepositLocked { vesting, .. } = command {
+                data.lock(amount, vesting, Clock::get()?.unix_timestamp)?;
+                Event::Locked {
+                    ledger: *account.key,
+                    amount: data.locked_amount,
+                    start: vesting.start,
+                    cliff: vesting.cliff,
+                    end: vesting.end,
+                }
+                .emit();
+            }
+        }
+        Command::Withdraw { amount } => {
+            let vault = next_account_info(accounts_iter)?;
+            let authority = next_account_info(accounts_iter)?;
+            let config_account = next_account_info(accounts_iter)?;
+            let fee_vault = next_account_info(accounts_iter)?;
+            check_vault(program_id, account, data, vault)?;
+            let config = load_unpaused_config(program_id, config_account)?;
+            check_fee_vault(program_id, fee_vault)?;
+            // A multisig can't spend lamports, so it names the account receiving them
+            let recipient = if is_multisig(program_id, authority) {
+                next_account_info(accounts_iter)?
+            } else {
+                authority
+            };
+            if *authority.key == data.authority {
+                check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+            } else {
+                spend_allowance(
+                    program_id,
+                    data,
+                    authority,
+                    accounts_iter.as_slice(),
+                    amount,
+                )?;
+            }
+            let now = Clock::get()?.unix_timestamp;
+            data.debit_unlocked(amount, now)?;
+            data.record_withdrawal(amount, now)?;
+
+            // The vault is owned by the program, so its lamports can be debited directly
+            let fee = config.fee(amount, now);
+            move_lamports(vault, fee_vault, fee)?;
+            move_lamports(vault, recipient, amount - fee)?;
+            record_operation(data, Operation::Withdraw, amount, recipient.key)?;
+            Event::Withdrew {
+                ledger: *account.key,
+                amount,
+                balance: data.balance,
+            }
+            .emit();
+        }
+        Command::Transfer { amount } => {
+            process_transfer(program_id, account, data, accounts_iter, amount)?;
+        }
+        Command::TokenDeposit { amount } => {
+            process_token_deposit(program_id, account, data, accounts_iter, amount)?;
+        }
+        Command::TokenWithdraw { amount } => {
+            process_token_withdraw(program_id, account, data, accounts_iter, amount)?;
+        }
+        Command::Approve { delegate, amount } => {
+            let authority = next_account_info(accounts_iter)?;
+            check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+            // Replaces any previous delegate and allowance
+            data.delegate = delegate;
+            data.delegated_amount = amount;
+            Event::Approved {
+                ledger: *account.key,
+                delegate,
+                amount,
+            }
+            .emit();
+        }
+        Command::Revoke => {
+            let authority = next_account_info(accounts_iter)?;
+            check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+            data.delegate = Pubkey::default();
+            data.delegated_amount = 0;
+            Event::Revoked {
+                ledger: *account.key,
+            }
+            .emit();
+        }
+        Command::SetAuthority {
+            authority: new_authority,
+        } => {
+            let authority = next_account_info(accounts_iter)?;
+            check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+            data.authority = new_authority;
+            Event::AuthorityChanged {
+                ledger: *account.key,
+                authority: new_authority,
+            }
+            .emit();
+        }
+        Command::SetWithdrawalLimit { limit } => {
+            let authority = next_account_info(accounts_iter)?;
+            check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+            // Only tightening applies at once, so a leaked key can't lift the limit before
+            // the owner notices, while the owner can lower it right away
+            let effective_at = data.schedule_limit(limit, Clock::get()?.unix_timestamp)?;
+            Event::WithdrawalLimitScheduled {
+                ledger: *account.key,
+                amount: limit.amount,
+                window: limit.window,
+                effective_at,
+            }
+            .emit();
+        }
+        Command::CreateEscrow { terms } => {
+            process_create_escrow(program_id, account, data, accounts_iter, terms)?;
+        }
+        Command::CheckBalance
+        | Command::Initialize
+        | Command::Migrate
+        | Command::Close
+        | Command::CreateMultisig { .. }
+        | Command::InitializeConfig
+        | Command::UpdateConfig { .. }
+        | Command::SetAdmin { .. }
+        | Command::CollectFees
+        | Command::ReleaseEscrow
+        | Command::RefundEscrow
+        | Command::InitializeWithHistory { .. } => {
+            unreachable!("handled by process_instruction")
+        }
+    }
+    Ok(())
+}
+
+/// Move `amount` from the ledger `account` to another ledger account, together with the
+/// lamports backing it.
+///
+/// Accounts (after the source ledger account):
+/// 1. `[writable]` Source vault
+/// 2. `[signer]` Source authority, or a multisig account not signing
+/// 3. `[writable]` Destination ledger account
+/// 4. `[writable]` Destination vault
+/// 5. `[]` Config account, derived with `find_config_address`
+/// 6. `[signer]` Multisig members, any number
+fn process_transfer<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    data: &mut Data,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+    amount: u64,
+) -> ProgramResult {
+    let vault = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+    let destination_vault = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    check_vault(program_id, account, data, vault)?;
+    load_unpaused_config(program_id, config)?;
+    check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+
+    // The destination must be another initialized ledger account of this program
+    if destination.key == account.key {
+        return Err(BankError::SelfTransfer.into());
+    }
+    if !destination.is_writable {
+        return Err(BankError::AccountNotWritable.into());
+    }
+    if *destination.owner != *program_id {
+        return Err(BankError::InvalidAccountOwner.into());
+    }
+    if destination.data_is_empty() {
+        return Err(BankError::UninitializedAccount.into());
+    }
+    let mut destination_data = Data::load(&destination.data.borrow())?;
+    check_vault(
+        program_id,
+        destination,
+        &destination_data,
+        destination_vault,
+    )?;
+
+    let now = Clock::get()?.unix_timestamp;
+    data.debit_unlocked(amount, now)?;
+    data.record_withdrawal(amount, now)?;
+    destination_data.credit(amount)?;
+    record_operation(data, Operation::TransferOut, amount, destination.key)?;
+    record_operation(
+        &mut destination_data,
+        Operation::TransferIn,
+        amount,
+        account.key,
+    )?;
+
+    // Both vaults are owned by the program
+    move_lamports(vault, destination_vault, amount)?;
+
+    destination_data.store(&mut destination.data.borrow_mut())?;
+    Event::Transferred {
+        source: *account.key,
+        destination: *destination.key,
+        amount,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Move `terms.amount` from the ledger `account` into a new escrow account, see `Escrow`.
+///
+/// The amount counts against the withdrawal limit but pays no withdrawal fee. The ledger
+/// counts the escrow in `Data::open_escrows` until it is released or refunded.
+///
+/// Accounts (after the source ledger account):
+/// 1. `[writable]` Vault
+/// 2. `[signer]` Authority, or a multisig account not signing
+/// 3. `[signer, writable]` Escrow account, a new keypair
+/// 4. `[signer, writable]` Payer, pays for the escrow account and gets the rent back
+/// 5. `[]` System program
+/// 6. `[]` Config account, derived with `find_config_address`
+/// 7. `[signer]` Multisig members, any number
+fn process_create_escrow<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    data: &mut Data,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+    terms: EscrowTerms,
+) -> ProgramResult {
+    let vault = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let payer = next_account_info(accounts_iter)?;
+    let system_program_account = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    check_vault(program_id, account, data, vault)?;
+    load_unpaused_config(program_id, config)?;
+    check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+
+    if *system_program_account.key != system_program::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if terms.recipient == *account.key {
+        return Err(BankError::SelfTransfer.into());
+    }
+    if terms.expiry_slot <= Clock::get()?.slot {
+        return Err(BankError::InvalidEscrow.into());
+    }
+    if !escrow_account.data_is_empty() || *escrow_account.owner == *program_id {
+        return Err(BankError::AlreadyInitialized.into());
+    }
+
+    let now = Clock::get()?.unix_timestamp;
+    data.debit_unlocked(terms.amount, now)?;
+    data.record_withdrawal(terms.amount, now)?;
+    data.open_escrows = data
+        .open_escrows
+        .checked_add(1)
+        .ok_or(BankError::Overflow)?;
+
+    invoke(
+        &system_instruction::create_account(
+            payer.key,
+            escrow_account.key,
+            Rent::get()?.minimum_balance(Escrow::LEN),
+            Escrow::LEN as u64,
+            program_id,
+        ),
+        &[
+            payer.clone(),
+            escrow_account.clone(),
+            system_program_account.clone(),
+        ],
+    )?;
+    let escrow = Escrow {
+        source: *account.key,
+        payer: *payer.key,
+        terms,
+        counted: true,
+    };
+    escrow.store(&mut escrow_account.data.borrow_mut())?;
+
+    // The escrow account is now owned by the program as well
+    move_lamports(vault, escrow_account, terms.amount)?;
+    record_operation(data, Operation::EscrowOut, terms.amount, escrow_account.key)?;
+    Event::EscrowCreated {
+        escrow: *escrow_account.key,
+        source: *account.key,
+        recipient: terms.recipient,
+        amount: terms.amount,
+        expiry_slot: terms.expiry_slot,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Read the escrow of the writable escrow `account`, whose rent goes back to `payer`.
+fn load_escrow(
+    program_id: &Pubkey,
+    account: &AccountInfo,
+    payer: &AccountInfo,
+) -> Result<Escrow, ProgramError> {
+    if !account.is_writable {
+        return Err(BankError::AccountNotWritable.into());
+    }
+    if *account.owner != *program_id {
+        return Err(BankError::InvalidAccountOwner.into());
+    }
+    let escrow = Escrow::load(&account.data.borrow())?;
+    if *payer.key != escrow.payer {
+        return Err(BankError::InvalidEscrow.into());
+    }
+    Ok(escrow)
+}
+
+/// Remove a paid out escrow from the open escrows of its source.
+fn uncount_escrow(source_data: &mut Data) -> Result<(), BankError> {
+    source_data.open_escrows = source_data
+        .open_escrows
+        .checked_sub(1)
+        .ok_or(BankError::Overflow)?;
+    Ok(())
+}
+
+/// Credit the amount of the escrow `account` to the ledger account `ledger`, move its
+/// lamports to the ledger's vault and the rent to `payer`, then wipe the escrow.
+fn pay_out_escrow(
+    program_id: &Pubkey,
+    account: &AccountInfo,
+    escrow: &Escrow,
+    ledger: &AccountInfo,
+    ledger_data: &mut Data,
+    vault: &AccountInfo,
+    payer: &AccountInfo,
+) -> ProgramResult {
+    check_vault(program_id, ledger, ledger_data, vault)?;
+    ledger_data.credit(escrow.terms.amount)?;
+    record_operation(
+        ledger_data,
+        Operation::EscrowIn,
+        escrow.terms.amount,
+        account.key,
+    )?;
+
+    move_lamports(account, vault, escrow.terms.amount)?;
+    move_lamports(account, payer, account.lamports())?;
+    // Like a closed ledger account, the escrow can't be paid out twice
+    account.data.borrow_mut().fill(0);
+
+    ledger_data.store(&mut ledger.data.borrow_mut())
+}
+
+/// Pay the escrow out to its recipient ledger account, before its expiry slot.
+///
+/// The arbiter releases by signing. If the terms allow it, the authority of the recipient
+/// ledger can release instead, a multisig authority with the signatures of its members.
+///
+/// Accounts:
+/// 0. `[writable]` Escrow account
+/// 1. `[writable]` Recipient ledger account
+/// 2. `[writable]` Recipient vault
+/// 3. `[signer]` Arbiter or recipient authority, or a multisig account not signing
+/// 4. `[writable]` Payer of the escrow account, gets the rent back
+/// 5. `[]` Config account, derived with `find_config_address`
+/// 6. `[writable]` Source ledger account, no longer counting the escrow as open
+/// 7. `[signer]` Multisig members, any number
+fn process_release_escrow(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let recipient = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let releaser = next_account_info(accounts_iter)?;
+    let payer = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    let source = next_account_info(accounts_iter)?;
+
+    let escrow = load_escrow(program_id, account, payer)?;
+    load_unpaused_config(program_id, config)?;
+    if Clock::get()?.slot >= escrow.terms.expiry_slot {
+        return Err(BankError::EscrowExpired.into());
+    }
+    if *recipient.key != escrow.terms.recipient || *source.key != escrow.source {
+        return Err(BankError::InvalidEscrow.into());
+    }
+    // The source of a counted escrow can't have been closed
+    if escrow.counted {
+        let mut source_data = load_ledger(program_id, source)?;
+        uncount_escrow(&mut source_data)?;
+        source_data.store(&mut source.data.borrow_mut())?;
+    }
+    // A closed recipient can be initialized again, or the escrow refunded after expiry
+    let mut recipient_data = load_ledger(program_id, recipient)?;
+
+    if *releaser.key == escrow.terms.arbiter {
+        if !releaser.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+    } else if escrow.terms.recipient_can_release && *releaser.key == recipient_data.authority {
+        check_authority(
+            program_id,
+            &recipient_data,
+            releaser,
+            accounts_iter.as_slice(),
+        )?;
+    } else {
+        return Err(BankError::NotEscrowReleaser.into());
+    }
+
+    pay_out_escrow(
+        program_id,
+        account,
+        &escrow,
+        recipient,
+        &mut recipient_data,
+        vault,
+        payer,
+    )?;
+    Event::EscrowReleased {
+        escrow: *account.key,
+        recipient: *recipient.key,
+        amount: escrow.terms.amount,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Pay the escrow back to its source ledger account, from its expiry slot. Anyone may
+/// call it.
+///
+/// The source ledger account can't be closed while it counts the escrow as open. The
+/// source of an escrow created before layout version 7 may have been closed, and must
+/// then be initialized again first.
+///
+/// Accounts:
+/// 0. `[writable]` Escrow account
+/// 1. `[writable]` Source ledger account
+/// 2. `[writable]` Source vault
+/// 3. `[writable]` Payer of the escrow account, gets the rent back
+/// 4. `[]` Config account, derived with `find_config_address`
+fn process_refund_escrow(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let source = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let payer = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+
+    let escrow = load_escrow(program_id, account, payer)?;
+    load_unpaused_config(program_id, config)?;
+    if Clock::get()?.slot < escrow.terms.expiry_slot {
+        return Err(BankError::EscrowNotExpired.into());
+    }
+    if *source.key != escrow.source {
+        return Err(BankError::InvalidEscrow.into());
+    }
+    let mut source_data = load_ledger(program_id, source)?;
+    if escrow.counted {
+        uncount_escrow(&mut source_data)?;
+    }
+
+    pay_out_escrow(
+        program_id,
+        account,
+        &escrow,
+        source,
+        &mut source_data,
+        vault,
+        payer,
+    )?;
+    Event::EscrowRefunded {
+        escrow: *account.key,
+        source: *source.key,
+        amount: escrow.terms.amount,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Retire the ledger account: sweep its balance to the authority, then move the rent of
+/// the ledger account, its vault and its token vaults to the destination and wipe the
+/// ledger data.
+///
+/// Token balances must be withdrawn first, no lamports may be locked, every escrow must
+/// be released or refunded, and the balance counts against the withdrawal limit and pays
+/// the withdrawal fee. A multisig authority
+/// can't spend lamports, so the destination then receives the balance as well.
+///
+/// A token vault still holding tokens sent to it directly, outside of the ledger, stays
+/// open and is reused if the ledger is initialized again.
+///
+/// Accounts (after the ledger account):
+/// 1. `[writable]` Vault
+/// 2. `[signer, writable]` Authority, receives the remaining balance
+/// 3. `[writable]` Destination of the reclaimed rent
+/// 4. `[]` Config account, derived with `find_config_address`
+/// 5. `[writable]` Fee vault, derived with `find_fee_vault_address`
+/// 6. `[]` SPL Token program, only if the ledger ever held tokens
+/// 7. `[writable]` Token vaults, one per entry of `Data::tokens` in the same order
+/// 8. `[signer]` Multisig members, any number
+fn process_close<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    data: &mut Data,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+) -> ProgramResult {
+    let vault = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+    let fee_vault = next_account_info(accounts_iter)?;
+    check_vault(program_id, account, data, vault)?;
+    let config = load_unpaused_config(program_id, config_account)?;
+    check_fee_vault(program_id, fee_vault)?;
+    check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+    if data.tokens.iter().any(|token| token.amount > 0) {
+        return Err(BankError::TokenBalanceNotZero.into());
+    }
+    let now = Clock::get()?.unix_timestamp;
+    if data.locked(now) > 0 {
+        return Err(BankError::FundsLocked.into());
+    }
+    // A refund pays back into the ledger, so it must still exist
+    if data.open_escrows > 0 {
+        return Err(BankError::EscrowsOpen.into());
+    }
+    data.record_withdrawal(data.balance, now)?;
+    if destination.key == account.key
+        || destination.key == vault.key
+        || destination.key == config_account.key
+        || destination.key == fee_vault.key
+    {
+        return Err(BankError::InvalidDestination.into());
+    }
+
+    if !data.tokens.is_empty() {
+        let token_program = next_account_info(accounts_iter)?;
+        check_token_program(token_program)?;
+        for token in &data.tokens {
+            let token_vault = next_account_info(accounts_iter)?;
+            let (token_vault_address, _) =
+                find_token_vault_address(program_id, account.key, &token.mint);
+            if *token_vault.key != token_vault_address {
+                return Err(BankError::InvalidTokenVault.into());
+            }
+            if TokenAccount::unpack(&token_vault.data.borrow())?.amount > 0 {
+                continue;
+            }
+            invoke_signed(
+                &spl_token::instruction::close_account(
+                    &spl_token::id(),
+                    token_vault.key,
+                    destination.key,
+                    vault.key,
+                    &[],
+                )?,
+                &[
+                    token_vault.clone(),
+                    destination.clone(),
+                    vault.clone(),
+                    token_program.clone(),
+                ],
+                &[&[VAULT_SEED, account.key.as_ref(), &[data.vault_bump]]],
+            )?;
+        }
+    }
+
+    let recipient = if is_multisig(program_id, authority) {
+        destination
+    } else {
+        authority
+    };
+    let fee = config.fee(data.balance, now);
+    move_lamports(vault, fee_vault, fee)?;
+    move_lamports(vault, recipient, data.balance - fee)?;
+    move_lamports(vault, destination, vault.lamports())?;
+    move_lamports(account, destination, account.lamports())?;
+
+    // Zeroed data no longer carries the discriminator, so the account can't be used
+    // again even if lamports are sent back to it within the same transaction
+    account.data.borrow_mut().fill(0);
+
+    Event::Closed {
+        ledger: *account.key,
+        destination: *destination.key,
+        balance: data.balance,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Move `amount` tokens from the depositor into the ledger's token vault for the mint,
+/// creating the token vault on the first deposit of that mint.
+///
+/// Accounts (after the ledger account):
+/// 1. `[]` Vault
+/// 2. `[writable]` Token vault, derived with `find_token_vault_address`
+/// 3. `[]` Mint
+/// 4. `[signer, writable]` Depositor, pays for the token vault and ledger growth
+/// 5. `[writable]` Depositor token account
+/// 6. `[]` SPL Token program
+/// 7. `[]` System program
+/// 8. `[]` Config account, derived with `find_config_address`
+fn process_token_deposit<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    data: &mut Data,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+    amount: u64,
+) -> ProgramResult {
+    let vault = next_account_info(accounts_iter)?;
+    let token_vault = next_account_info(accounts_iter)?;
+    let mint = next_account_info(accounts_iter)?;
+    let depositor = next_account_info(accounts_iter)?;
+    let source = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let system_program_account = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    check_vault(program_id, account, data, vault)?;
+    load_unpaused_config(program_id, config)?;
+    check_token_program(token_program)?;
+
+    let (token_vault_address, token_vault_bump) =
+        find_token_vault_address(program_id, account.key, mint.key);
+    if *token_vault.key != token_vault_address {
+        return Err(BankError::InvalidTokenVault.into());
+    }
+
+    // First deposit of this mint: create the token account owned by the vault
+    if token_vault.data_is_empty() {
+        create_pda_account(
+            depositor,
+            token_vault,
+            TokenAccount::LEN,
+            &spl_token::id(),
+            system_program_account,
+            &[
+                TOKEN_VAULT_SEED,
+                account.key.as_ref(),
+                mint.key.as_ref(),
+                &[token_vault_bump],
+            ],
+        )?;
+        invoke(
+            &spl_token::instruction::initialize_account3(
+                &spl_token::id(),
+                token_vault.key,
+                mint.key,
+                vault.key,
+            )?,
+            &[token_vault.clone(), mint.clone(), token_program.clone()],
+        )?;
+    }
+
+    let decimals = Mint::unpack(&mint.data.borrow())?.decimals;
+    invoke(
+        &spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            source.key,
+            mint.key,
+            token_vault.key,
+            depositor.key,
+            &[],
+            amount,
+            decimals,
+        )?,
+        &[
+            source.clone(),
+            mint.clone(),
+            token_vault.clone(),
+            depositor.clone(),
+            token_program.clone(),
+        ],
+    )?;
+
+    data.credit_token(mint.key, amount)?;
+    record_operation(data, Operation::TokenDeposit, amount, source.key)?;
+
+    // Grow the ledger account to fit a new mint entry
+    if data.size() > account.data_len() {
+        grow_account(account, data.size(), depositor, system_program_account)?;
+    }
+    Event::TokenDeposited {
+        ledger: *account.key,
+        mint: *mint.key,
+        amount,
+        balance: data.token_balance(mint.key),
+    }
+    .emit();
+    Ok(())
+}
+
+/// Move `amount` tokens from the ledger's token vault for the mint to a destination
+/// token account.
+///
+/// Accounts (after the ledger account):
+/// 1. `[]` Vault
+/// 2. `[writable]` Token vault, derived with `find_token_vault_address`
+/// 3. `[]` Mint
+/// 4. `[signer]` Authority
+/// 5. `[writable]` Destination token account
+/// 6. `[]` SPL Token program
+/// 7. `[]` Config account, derived with `find_config_address`
+/// 8. `[signer]` Multisig members, any number
+fn process_token_withdraw<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    data: &mut Data,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+    amount: u64,
+) -> ProgramResult {
+    let vault = next_account_info(accounts_iter)?;
+    let token_vault = next_account_info(accounts_iter)?;
+    let mint = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    check_vault(program_id, account, data, vault)?;
+    load_unpaused_config(program_id, config)?;
+    check_authority(program_id, data, authority, accounts_iter.as_slice())?;
+    check_token_program(token_program)?;
+
+    let (token_vault_address, _) = find_token_vault_address(program_id, account.key, mint.key);
+    if *token_vault.key != token_vault_address {
+        return Err(BankError::InvalidTokenVault.into());
+    }
+    data.debit_token(mint.key, amount)?;
+
+    // The vault owns the token vault, so it signs the transfer out
+    let decimals = Mint::unpack(&mint.data.borrow())?.decimals;
+    invoke_signed(
+        &spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            token_vault.key,
+            mint.key,
+            destination.key,
+            vault.key,
+            &[],
+            amount,
+            decimals,
+        )?,
+        &[
+            token_vault.clone(),
+            mint.clone(),
+            destination.clone(),
+            vault.clone(),
+            token_program.clone(),
+        ],
+        &[&[VAULT_SEED, account.key.as_ref(), &[data.vault_bump]]],
+    )?;
+    record_operation(data, Operation::TokenWithdraw, amount, destination.key)?;
+    Event::TokenWithdrew {
+        ledger: *account.key,
+        mint: *mint.key,
+        amount,
+        balance: data.token_balance(mint.key),
+    }
+    .emit();
+    Ok(())
+}
+
+/// Ensure `authority` is the authority recorded in `data` and signed the transaction.
+///
+/// A multisig authority does not sign, its members do and are passed as `signers`.
+fn check_authority(
+    program_id: &Pubkey,
+    data: &Data,
+    authority: &AccountInfo,
+    signers: &[AccountInfo],
+) -> ProgramResult {
+    if *authority.key != data.authority {
+        return Err(BankError::WrongAuthority.into());
+    }
+    if is_multisig(program_id, authority) {
+        return Multisig::load(&authority.data.borrow())?.check_signers(signers);
+    }
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
+
+/// Whether `account` is a multisig account of this program.
+fn is_multisig(program_id: &Pubkey, account: &AccountInfo) -> bool {
+    *account.owner == *program_id && Multisig::load(&account.data.borrow()).is_ok()
+}
+
+/// Ensure `delegate` is the approved delegate and signed, or is a multisig with enough
+/// of its members among `signers`, and take `amount` from its allowance. The delegate is
+/// removed once the allowance is used up.
+fn spend_allowance(
+    program_id: &Pubkey,
+    data: &mut Data,
+    delegate: &AccountInfo,
+    signers: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    if *delegate.key != data.delegate {
+        return Err(BankError::WrongAuthority.into());
+    }
+    if is_multisig(program_id, delegate) {
+        Multisig::load(&delegate.data.borrow())?.check_signers(signers)?;
+    } else if !delegate.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    data.delegated_amount = data
+        .delegated_amount
+        .checked_sub(amount)
+        .ok_or(BankError::AllowanceExceeded)?;
+    if data.delegated_amount == 0 {
+        data.delegate = Pubkey::default();
+    }
+    Ok(())
+}
+
+/// Create the program derived `account` of `space` bytes owned by `owner`, with `payer`
+/// making it rent-exempt. `seeds` derive the address of `account`.
+///
+/// Anyone can send lamports to the address beforehand, which would make `create_account`
+/// fail, so only the missing lamports are transferred before allocating and assigning.
+fn create_pda_account<'a>(
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    space: usize,
+    owner: &Pubkey,
+    system_program_account: &AccountInfo<'a>,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let lamports = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(account.lamports());
+    if lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, lamports),
+            &[
+                payer.clone(),
+                account.clone(),
+                system_program_account.clone(),
+            ],
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(account.key, space as u64),
+        &[account.clone(), system_program_account.clone()],
+        &[seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(account.key, owner),
+        &[account.clone(), system_program_account.clone()],
+        &[seeds],
+    )
+}
+
+/// Reallocate the program-owned `account` to `new_len` bytes, with `payer` topping up its
+/// lamports to stay rent-exempt.
+fn grow_account<'a>(
+    account: &AccountInfo<'a>,
+    new_len: usize,
+    payer: &AccountInfo<'a>,
+    system_program_account: &AccountInfo<'a>,
+) -> ProgramResult {
+    let required = Rent::get()?.minimum_balance(new_len);
+    if required > account.lamports() {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, required - account.lamports()),
+            &[
+                payer.clone(),
+                account.clone(),
+                system_program_account.clone(),
+            ],
+        )?;
+    }
+    account.realloc(new_len, false)
+}
+
+/// Move lamports out of the program-owned account `from` into `to`.
+fn move_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> ProgramResult {
+    let from_lamports = from
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(BankError::InsufficientFunds)?;
+    let to_lamports = to
+        .lamports()
+        .checked_add(amount)
+        .ok_or(BankError::Overflow)?;
+    **from.try_borrow_mut_lamports()? = from_lamports;
+    **to.try_borrow_mut_lamports()? = to_lamports;
+    Ok(())
+}
+
+/// Read the config from `config`, which must be at `find_config_address`, and ensure
+/// the program is not paused.
+fn load_unpaused_config(program_id: &Pubkey, config: &AccountInfo) -> Result<Config, ProgramError> {
+    let config = load_config(program_id, config)?;
+    if config.paused {
+        return Err(BankError::Paused.into());
+    }
+    Ok(config)
+}
+
+/// Read the config from `config`, which must be at `find_config_address`. A config account
+/// that has not been created yet holds the default settings.
+fn load_config(program_id: &Pubkey, config: &AccountInfo) -> Result<Config, ProgramError> {
+    let (address, _) = find_config_address(program_id);
+    if *config.key != address {
+        return Err(BankError::InvalidConfig.into());
+    }
+    if config.data_is_empty() {
+        return Ok(Config::default());
+    }
+    if *config.owner != *program_id {
+        return Err(BankError::InvalidConfig.into());
+    }
+    Ok(Config::load(&config.data.borrow())?)
+}
+
+/// Ensure `token_program` is the SPL Token program.
+fn check_token_program(token_program: &AccountInfo) -> ProgramResult {
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(())
+}
+
+/// Ensure `fee_vault` is at `find_fee_vault_address`.
+fn check_fee_vault(program_id: &Pubkey, fee_vault: &AccountInfo) -> ProgramResult {
+    let (address, _) = find_fee_vault_address(program_id);
+    if *fee_vault.key != address {
+        return Err(BankError::InvalidFeeVault.into());
+    }
+    Ok(())
+}
+
+/// Ensure `vault` is the vault of the ledger `account`.
+fn check_vault(
+    program_id: &Pubkey,
+    account: &AccountInfo,
+    data: &Data,
+    vault: &AccountInfo,
+) -> ProgramResult {
+    let address = Pubkey::create_program_address(
+        &[VAULT_SEED, account.key.as_ref(), &[data.vault_bump]],
+        program_id,
+    )
+    .map_err(|_| BankError::InvalidVault)?;
+    if *vault.key != address {
+        return Err(BankError::InvalidVault.into());
+    }
+    if *vault.owner != *program_id {
+        return Err(BankError::InvalidVault.into());
+    }
+    Ok(())
+}
+
+/// Create the ledger account of the signing user and its vault at their program-derived
+/// addresses.
+///
+/// With a `history_capacity`, the ledger account then grows to hold that many history
+/// entries.
+///
+/// Accounts:
+/// 0. `[signer, writable]` User, pays for the accounts and becomes the authority
+/// 1. `[writable]` Ledger account, derived with `find_ledger_address`
+/// 2. `[writable]` Vault, derived with `find_vault_address`
+/// 3. `[]` System program
+fn process_initialize(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    history_capacity: u8,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let system_program_account = next_account_info(accounts_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if *system_program_account.key != system_program::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let (address, bump) = find_ledger_address(program_id, user.key);
+    if *account.key != address {
+        return Err(BankError::InvalidLedgerAddress.into());
+    }
+    if !account.data_is_empty() || *account.owner == *program_id {
+        return Err(BankError::AlreadyInitialized.into());
+    }
+    let (vault_address, vault_bump) = find_vault_address(program_id, account.key);
+    if *vault.key != vault_address {
+        return Err(BankError::InvalidVault.into());
+    }
+
+    // Allocate rent-exempt accounts owned by this program
+    create_pda_account(
+        user,
+        account,
+        Data::LEN,
+        program_id,
+        system_program_account,
+        &[LEDGER_SEED, user.key.as_ref(), &[bump]],
+    )?;
+    create_pda_account(
+        user,
+        vault,
+        0,
+        program_id,
+        system_program_account,
+        &[VAULT_SEED, account.key.as_ref(), &[vault_bump]],
+    )?;
+
+    let mut data = Data::new(*user.key, vault_bump);
+    if history_capacity > 0 {
+        data.history.capacity = history_capacity;
+        grow_account(account, data.size(), user, system_program_account)?;
+    }
+    data.store(&mut account.data.borrow_mut())?;
+
+    Event::Initialized {
+        ledger: *account.key,
+        authority: *user.key,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Create a multisig account that can be set as the authority of ledger accounts.
+///
+/// Accounts:
+/// 0. `[signer, writable]` Multisig account, a new keypair
+/// 1. `[signer, writable]` Payer
+/// 2. `[]` System program
+/// 3. `[]` Members, at least `threshold` and at most `MAX_SIGNERS` distinct keys
+fn process_create_multisig(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    threshold: u8,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let payer = next_account_info(accounts_iter)?;
+    let system_program_account = next_account_info(accounts_iter)?;
+
+    if *system_program_account.key != system_program::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if !account.data_is_empty() || *account.owner == *program_id {
+        return Err(BankError::AlreadyInitialized.into());
+    }
+    let signers: Vec<Pubkey> = accounts_iter.map(|signer| *signer.key).collect();
+    let distinct = signers
+        .iter()
+        .enumerate()
+        .all(|(i, signer)| !signers[..i].contains(signer));
+    if threshold == 0
+        || usize::from(threshold) > signers.len()
+        || signers.len() > MAX_SIGNERS
+        || !distinct
+    {
+        return Err(BankError::InvalidMultisig.into());
+    }
+
+    let multisig = Multisig { threshold, signers };
+    invoke(
+        &system_instruction::create_account(
+            payer.key,
+            account.key,
+            Rent::get()?.minimum_balance(multisig.size()),
+            multisig.size() as u64,
+            program_id,
+        ),
+        &[
+            payer.clone(),
+            account.clone(),
+            system_program_account.clone(),
+        ],
+    )?;
+    multisig.store(&mut account.data.borrow_mut())?;
+
+    Event::MultisigCreated {
+        multisig: *account.key,
+        threshold: multisig.threshold,
+        signers: multisig.signers.len() as u8,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Create the config account, with the signer as admin and treasury, and the fee vault.
+///
+/// Only the upgrade authority of the program may do so, otherwise the first caller after
+/// a deploy could charge any fee to a treasury of their own. A program without upgrade
+/// authority keeps the default settings.
+///
+/// Accounts:
+/// 0. `[signer, writable]` Admin, the upgrade authority, pays for the accounts
+/// 1. `[writable]` Config account, derived with `find_config_address`
+/// 2. `[]` System program
+/// 3. `[]` Program data account of this program
+/// 4. `[writable]` Fee vault, derived with `find_fee_vault_address`
+fn process_initialize_config(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    let system_program_account = next_account_info(accounts_iter)?;
+    let program_data = next_account_info(accounts_iter)?;
+    let fee_vault = next_account_info(accounts_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if *system_program_account.key != system_program::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if *program_data.key != bpf_loader_upgradeable::get_program_data_address(program_id)
+        || *program_data.owner != bpf_loader_upgradeable::id()
+    {
+        return Err(BankError::InvalidProgramData.into());
+    }
+    match deserialize(&program_data.data.borrow()) {
+        Ok(UpgradeableLoaderState::ProgramData {
+            upgrade_authority_address: Some(authority),
+            ..
+        }) if authority == *admin.key => {}
+        Ok(UpgradeableLoaderState::ProgramData { .. }) => {
+            return Err(BankError::NotAdmin.into());
+        }
+        _ => return Err(BankError::InvalidProgramData.into()),
+    }
+    let (address, bump) = find_config_address(program_id);
+    if *config.key != address {
+        return Err(BankError::InvalidConfig.into());
+    }
+    if !config.data_is_empty() || *config.owner == *program_id {
+        return Err(BankError::AlreadyInitialized.into());
+    }
+    let (fee_vault_address, fee_vault_bump) = find_fee_vault_address(program_id);
+    if *fee_vault.key != fee_vault_address {
+        return Err(BankError::InvalidFeeVault.into());
+    }
+
+    create_pda_account(
+        admin,
+        config,
+        Config::LEN,
+        program_id,
+        system_program_account,
+        &[CONFIG_SEED, &[bump]],
+    )?;
+    create_pda_account(
+        admin,
+        fee_vault,
+        0,
+        program_id,
+        system_program_account,
+        &[FEE_VAULT_SEED, &[fee_vault_bump]],
+    )?;
+
+    Config {
+        admin: *admin.key,
+        paused: false,
+        fee_bps: 0,
+        pending_fee_bps: 0,
+        pending_fee_at: 0,
+        treasury: *admin.key,
+        bump,
+    }
+    .store(&mut config.data.borrow_mut())?;
+
+    Event::ConfigCreated {
+        config: *config.key,
+        admin: *admin.key,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Apply `Command::UpdateConfig` or `Command::SetAdmin`.
+///
+/// Accounts:
+/// 0. `[writable]` Config account, derived with `find_config_address`
+/// 1. `[signer]` Admin
+fn process_update_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    command: Command,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let config_account = next_account_info(accounts_iter)?;
+    let admin = next_account_info(accounts_iter)?;
+
+    if config_account.data_is_empty() {
+        return Err(BankError::UninitializedAccount.into());
+    }
+    let mut config = load_config(program_id, config_account)?;
+    if *admin.key != config.admin {
+        return Err(BankError::NotAdmin.into());
+    }
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    match command {
+        Command::UpdateConfig {
+            paused,
+            fee_bps,
+            treasury,
+        } => {
+            // Lowering the fee applies at once, raising it gives users time to withdraw first
+            let effective_at = config.schedule_fee(fee_bps, Clock::get()?.unix_timestamp)?;
+            config.paused = paused;
+            config.treasury = treasury;
+            Event::ConfigUpdated {
+                config: *config_account.key,
+                paused,
+                fee_bps,
+                fee_effective_at: effective_at,
+                treasury,
+            }
+            .emit();
+        }
+        Command::SetAdmin { admin } => {
+            config.admin = admin;
+            Event::AdminChanged {
+                config: *config_account.key,
+                admin,
+            }
+            .emit();
+        }
+        _ => unreachable!("only config updates are routed here"),
+    }
+    config.store(&mut config_account.data.borrow_mut())
+}
+
+/// Send the fees held by the fee vault to the treasury. Anyone may call it.
+///
+/// Accounts:
+/// 0. `[]` Config account, derived with `find_config_address`
+/// 1. `[writable]` Fee vault, derived with `find_fee_vault_address`
+/// 2. `[writable]` Treasury
+fn process_collect_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let config_account = next_account_info(accounts_iter)?;
+    let fee_vault = next_account_info(accounts_iter)?;
+    let treasury = next_account_info(accounts_iter)?;
+
+    if config_account.data_is_empty() {
+        return Err(BankError::UninitializedAccount.into());
+    }
+    let config = load_config(program_id, config_account)?;
+    if *treasury.key != config.treasury {
+        return Err(BankError::InvalidTreasury.into());
+    }
+
+    check_fee_vault(program_id, fee_vault)?;
+
+    // Everything above the rent-exempt minimum is fees
+    let fees = fee_vault
+        .lamports()
+        .saturating_sub(Rent::get()?.minimum_balance(0));
+    move_lamports(fee_vault, treasury, fees)?;
+    Event::FeesCollected {
+        treasury: *treasury.key,
+        amount: fees,
+    }
+    .emit();
+    Ok(())
+}
+
+/// Publish the lamport balance of the ledger account as little-endian `u64` return data.
+///
+/// Accounts:
+/// 0. `[]` Ledger account
+fn process_check_balance(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+
+    if *account.owner != *program_id {
+        return Err(BankError::InvalidAccountOwner.into());
+    }
+    if account.data_is_empty() {
+        return Err(BankError::UninitializedAccount.into());
+    }
+    let data = Data::load(&account.data.borrow())?;
+
+    msg!("Current balance: {}", data.balance);
+    set_return_data(&data.balance.to_le_bytes());
+    Ok(())
+}
+
+/// Upgrade a ledger account written with an older layout to `LAYOUT_VERSION` in place.
+///
+/// Version 1 accounts were created from a keypair, so that keypair must sign to prove
+/// ownership. The signing authority becomes the account authority and funds its vault
+/// with the recorded balance, which version 1 never backed with lamports.
+///
+/// Later versions keep their authority and vault, and any signer can pay for the
+/// larger account.
+///
+/// Accounts:
+/// 0. `[writable]` Ledger account, signer if version 1
+/// 1. `[signer, writable]` Authority of a version 1 account, otherwise any payer
+/// 2. `[writable]` Vault, derived with `find_vault_address`
+/// 3. `[]` System program
+fn process_migrate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let system_program_account = next_account_info(accounts_iter)?;
+
+    if !account.is_writable {
+        return Err(BankError::AccountNotWritable.into());
+    }
+    if *account.owner != *program_id {
+        return Err(BankError::InvalidAccountOwner.into());
+    }
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if *system_program_account.key != system_program::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let version = Data::version(&account.data.borrow())?;
+    if version == 1 && !account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let data = match version {
+        1 => {
+            let legacy: LegacyData =
+                deserialize(&account.data.borrow()).map_err(|_| BankError::InvalidAccountData)?;
+            let (vault_address, vault_bump) = find_vault_address(program_id, account.key);
+            if *vault.key != vault_address {
+                return Err(BankError::InvalidVault.into());
+            }
+
+            // Create the vault holding the legacy balance
+            create_pda_account(
+                authority,
+                vault,
+                0,
+                program_id,
+                system_program_account,
+                &[VAULT_SEED, account.key.as_ref(), &[vault_bump]],
+            )?;
+            if legacy.balance > 0 {
+                invoke(
+                    &system_instruction::transfer(authority.key, vault.key, legacy.balance),
+                    &[
+                        authority.clone(),
+                        vault.clone(),
+                        system_program_account.clone(),
+                    ],
+                )?;
+            }
+
+            Data {
+                number: legacy.number,
+                balance: legacy.balance,
+                ..Data::new(*authority.key, vault_bump)
+            }
+        }
+        LAYOUT_VERSION => return Err(BankError::AlreadyMigrated.into()),
+        version if version < LAYOUT_VERSION => Data::load_older(&account.data.borrow())?,
+        _ => return Err(BankError::UnsupportedAccountVersion.into()),
+    };
+
+    if data.size() > account.data_len() {
+        grow_account(account, data.size(), authority, system_program_account)?;
+    }
+    data.store(&mut account.data.borrow_mut())?;
+
+    Event::Migrated {
+        ledger: *account.key,
+        from_version: version,
+        to_version: LAYOUT_VERSION,
+    }
+    .emit();
+    Ok(())
+}
+
+// Define the instruction data structure
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Accounts: ledger `[writable]`, vault `[writable]`, depositor `[signer, writable]`,
+    /// system program, config
+    Deposit {
+        amount: u64,
+    },
+    /// Accounts: ledger `[writable]`, vault `[writable]`, authority or delegate
+    /// `[signer, writable]`, config, fee vault `[writable]`. A multisig authority or
+    /// delegate is followed by the recipient `[writable]` and its signing members
+    /// `[signer]`.
+    Withdraw {
+        amount: u64,
+    },
+    /// Accounts: ledger, returns the balance as little-endian `u64` return data
+    CheckBalance,
+    Initialize,
+    /// Accounts: see `process_token_deposit`
+    TokenDeposit {
+        amount: u64,
+    },
+    /// Accounts: see `process_token_withdraw`
+    TokenWithdraw {
+        amount: u64,
+    },
+    /// Accounts: see `process_transfer`
+    Transfer {
+        amount: u64,
+    },
+    /// Accounts: see `process_migrate`
+    Migrate,
+    /// Accounts: see `process_close`
+    Close,
+    /// Let `delegate` withdraw up to `amount`, replacing any previous delegate.
+    ///
+    /// Accounts: ledger `[writable]`, authority `[signer]`
+    Approve {
+        delegate: Pubkey,
+        amount: u64,
+    },
+    /// Remove the delegate.
+    ///
+    /// Accounts: ledger `[writable]`, authority `[signer]`
+    Revoke,
+    /// Accounts: see `process_create_multisig`
+    CreateMultisig {
+        threshold: u8,
+    },
+    /// Hand the ledger over to `authority`, e.g. a multisig account.
+    ///
+    /// Accounts: ledger `[writable]`, authority `[signer]`, multisig members `[signer]`
+    SetAuthority {
+        authority: Pubkey,
+    },
+    /// Deposit `amount` that the authority can only withdraw as `vesting` releases it.
+    ///
+    /// Accounts: as `Deposit`, then authority `[signer]`, multisig members `[signer]`
+    DepositLocked {
+        amount: u64,
+        vesting: VestingSchedule,
+    },
+    /// Replace the withdrawal limit, after `LIMIT_CHANGE_DELAY` unless the new limit is
+    /// within the current one.
+    ///
+    /// Accounts: ledger `[writable]`, authority `[signer]`, multisig members `[signer]`
+    SetWithdrawalLimit {
+        limit: WithdrawalLimit,
+    },
+    /// Accounts: see `process_initialize_config`
+    InitializeConfig,
+    /// Replace the pause switch, fee and treasury. A fee increase takes effect after
+    /// `FEE_CHANGE_DELAY`, anything else at once.
+    ///
+    /// Accounts: see `process_update_config`
+    UpdateConfig {
+        paused: bool,
+        fee_bps: u16,
+        treasury: Pubkey,
+    },
+    /// Hand the admin role over to `admin`.
+    ///
+    /// Accounts: see `process_update_config`
+    SetAdmin {
+        admin: Pubkey,
+    },
+    /// Accounts: see `process_collect_fees`
+    CollectFees,
+    /// Accounts: see `process_create_escrow`
+    CreateEscrow {
+        terms: EscrowTerms,
+    },
+    /// Accounts: see `process_release_escrow`
+    ReleaseEscrow,
+    /// Accounts: see `process_refund_escrow`
+    RefundEscrow,
+    /// `Initialize` with room for the last `capacity` operations in the history, at most
+    /// `MAX_HISTORY_LEN`.
+    ///
+    /// Accounts: see `process_initialize`
+    InitializeWithHistory {
+        capacity: u8,
+    },
+}
+
+impl Command {
+    /// Whether the command must carry the nonce of the ledger account: the commands
+    /// signed by its authority or delegate.
+    ///
+    /// Deposits change the ledger account too but are exempt on purpose: anyone can make
+    /// them, so consuming the nonce would let anyone invalidate instructions the authority
+    /// signed in advance. They neither carry the nonce nor advance it.
+    pub fn needs_nonce(&self) -> bool {
+        match self {
+            Command::Withdraw { .. }
+            | Command::TokenWithdraw { .. }
+            | Command::Transfer { .. }
+            | Command::Close
+            | Command::Approve { .. }
+            | Command::Revoke
+            | Command::SetAuthority { .. }
+            | Command::DepositLocked { .. }
+            | Command::SetWithdrawalLimit { .. }
+            | Command::CreateEscrow { .. } => true,
+            Command::Deposit { .. }
+            | Command::TokenDeposit { .. }
+            | Command::CheckBalance
+            | Command::Initialize
+            | Command::Migrate
+            | Command::CreateMultisig { .. }
+            | Command::InitializeConfig
+            | Command::UpdateConfig { .. }
+            | Command::SetAdmin { .. }
+            | Command::CollectFees
+            | Command::ReleaseEscrow
+            | Command::RefundEscrow
+            | Command::InitializeWithHistory { .. } => false,
+        }
+    }
+
+    /// Whether the command can be part of a `BatchInstruction`: the commands that change
+    /// the ledger account, except `Close`.
+    pub fn is_batchable(&self) -> bool {
+        match self {
+            Command::Deposit { .. } | Command::TokenDeposit { .. } => true,
+            Command::Close => false,
+            _ => self.needs_nonce(),
+        }
+    }
+
+    /// Append the encoding of the command to `data`: a one-byte tag, then the public key
+    /// and amount of the command if it has them, integers in little-endian.
+    /// The threshold of `CreateMultisig` and the capacity of `InitializeWithHistory` are a
+    /// single byte after the tag, the schedule of
+    /// `DepositLocked` follows the amount as three `i64`, the window of
+    /// `SetWithdrawalLimit` as one, and the treasury of `UpdateConfig` is followed by the
+    /// paused flag as a byte and the fee as a `u16`. The terms of `CreateEscrow` follow
+    /// the tag in field order, the flag as a byte.
+    ///
+    /// Tags match the variant order of `Command`, skipping `BatchInstruction::TAG`.
+    pub fn pack_into(&self, data: &mut Vec<u8>) {
+        let (tag, key, amount) = match *self {
+            Command::Deposit { amount } => (0, None, Some(amount)),
+            Command::Withdraw { amount } => (1, None, Some(amount)),
+            Command::CheckBalance => (2, None, None),
+            Command::Initialize => (3, None, None),
+            Command::TokenDeposit { amount } => (4, None, Some(amount)),
+            Command::TokenWithdraw { amount } => (5, None, Some(amount)),
+            Command::Transfer { amount } => (6, None, Some(amount)),
+            Command::Migrate => (7, None, None),
+            Command::Close => (8, None, None),
+            Command::Approve { delegate, amount } => (9, Some(delegate), Some(amount)),
+            Command::Revoke => (10, None, None),
+            Command::CreateMultisig { .. } => (11, None, None),
+            Command::SetAuthority { authority } => (12, Some(authority), None),
+            Command::DepositLocked { amount, .. } => (13, None, Some(amount)),
+            Command::SetWithdrawalLimit { limit } => (14, None, Some(limit.amount)),
+            Command::InitializeConfig => (15, None, None),
+            Command::UpdateConfig { treasury, .. } => (16, Some(treasury), None),
+            Command::SetAdmin { admin } => (17, Some(admin), None),
+            Command::CollectFees => (18, None, None),
+            Command::CreateEscrow { terms } => (20, Some(terms.recipient), None),
+            Command::ReleaseEscrow => (21, None, None),
+            Command::RefundEscrow => (22, None, None),
+            Command::InitializeWithHistory { .. } => (23, None, None),
+        };
+        data.push(tag);
+        if let Command::CreateMultisig { threshold: byte }
+        | Command::InitializeWithHistory { capacity: byte } = *self
+        {
+            data.push(byte);
+        }
+        if let Some(key) = key {
+            data.extend_from_slice(key.as_ref());
+        }
+        if let Some(amount) = amount {
+            data.extend_from_slice(&amount.to_le_bytes());
+        }
+        if let Command::DepositLocked { vesting, .. } = *self {
+            data.extend_from_slice(&vesting.start.to_le_bytes());
+            data.extend_from_slice(&vesting.cliff.to_le_bytes());
+            data.extend_from_slice(&vesting.end.to_le_bytes());
+        }
+        if let Command::SetWithdrawalLimit { limit } = *self {
+            data.extend_from_slice(&limit.window.to_le_bytes());
+        }
+        if let Command::UpdateConfig {
+            paused, fee_bps, ..
+        } = *self
+        {
+            data.push(paused.into());
+            data.extend_from_slice(&fee_bps.to_le_bytes());
+        }
+        if let Command::CreateEscrow { terms } = *self {
+            data.extend_from_slice(terms.arbiter.as_ref());
+            data.extend_from_slice(&terms.amount.to_le_bytes());
+            data.extend_from_slice(&terms.expiry_slot.to_le_bytes());
+            data.push(terms.recipient_can_release.into());
+        }
+    }
+
+    /// Decode a command encoded by `pack_into` from the start of `input`, advancing it
+    /// past the command.
+    pub fn unpack_from(input: &mut &[u8]) -> Result<Self, BankError> {
+        Ok(match unpack_u8(input)? {
+            0 => Command::Deposit {
+                amount: unpack_u64(input)?,
+            },
+            1 => Command::Withdraw {
+                amount: unpack_u64(input)?,
+            },
+            2 => Command::CheckBalance,
+            3 => Command::Initialize,
+            4 => Command::TokenDeposit {
+                amount: unpack_u64(input)?,
+            },
+            5 => Command::TokenWithdraw {
+                amount: unpack_u64(input)?,
+            },
+            6 => Command::Transfer {
+                amount: unpack_u64(input)?,
+            },
+            7 => Command::Migrate,
+            8 => Command::Close,
+            9 => Command::Approve {
+                delegate: unpack_pubkey(input)?,
+                amount: unpack_u64(input)?,
+            },
+            10 => Command::Revoke,
+            11 => Command::CreateMultisig {
+                threshold: unpack_u8(input)?,
+            },
+            12 => Command::SetAuthority {
+                authority: unpack_pubkey(input)?,
+            },
+            13 => Command::DepositLocked {
+                amount: unpack_u64(input)?,
+                vesting: VestingSchedule {
+                    start: unpack_u64(input)? as i64,
+                    cliff: unpack_u64(input)? as i64,
+                    end: unpack_u64(input)? as i64,
+                },
+            },
+            14 => Command::SetWithdrawalLimit {
+                limit: WithdrawalLimit {
+                    amount: unpack_u64(input)?,
+                    window: unpack_u64(input)? as i64,
+                },
+            },
+            15 => Command::InitializeConfig,
+            16 => {
+                let treasury = unpack_pubkey(input)?;
+                Command::UpdateConfig {
+                    paused: unpack_bool(input)?,
+                    fee_bps: unpack_u16(input)?,
+                    treasury,
+                }
+            }
+            17 => Command::SetAdmin {
+                admin: unpack_pubkey(input)?,
+            },
+            18 => Command::CollectFees,
+            20 => Command::CreateEscrow {
+                terms: EscrowTerms {
+                    recipient: unpack_pubkey(input)?,
+                    arbiter: unpack_pubkey(input)?,
+                    amount: unpack_u64(input)?,
+                    expiry_slot: unpack_u64(input)?,
+                    recipient_can_release: unpack_bool(input)?,
+                },
+            },
+            21 => Command::ReleaseEscrow,
+            22 => Command::RefundEscrow,
+            23 => Command::InitializeWithHistory {
+                capacity: unpack_u8(input)?,
+            },
+            _ => return Err(BankError::InvalidInstruction),
+        })
+    }
+}
+
+// Define the instruction struct
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommandInstruction {
+    pub command: Command,
+    /// Sequence number of the ledger account, required if `Command::needs_nonce`
+    pub nonce: u64,
+}
+
+impl CommandInstruction {
+    /// Instruction data for `command` at the ledger's current `nonce`.
+    pub fn new(command: Command, nonce: u64) -> Self {
+        CommandInstruction { command, nonce }
+    }
+
+    /// Encode the instruction: the command as encoded by `Command::pack_into`, then the
+    /// nonce in little-endian for the commands that need it.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(1 + 32 + 8 + 8);
+        self.command.pack_into(&mut data);
+        if self.command.needs_nonce() {
+            data.extend_from_slice(&self.nonce.to_le_bytes());
+        }
+        data
+    }
+
+    /// Decode instruction data produced by `pack`.
+    pub fn unpack(input: &[u8]) -> Result<Self, BankError> {
+        let mut rest = input;
+        let command = Command::unpack_from(&mut rest)?;
+        let nonce = if command.needs_nonce() {
+            unpack_u64(&mut rest)?
+        } else {
+            0
+        };
+        if !rest.is_empty() {
+            return Err(BankError::InvalidInstruction);
+        }
+        Ok(CommandInstruction { command, nonce })
+    }
+
+    /// Decode the bincode encoding of `LegacyCommandInstruction` sent by older clients.
+    ///
+    /// The legacy encoding has no nonce, so the instruction gets nonce 0: a legacy
+    /// withdrawal only succeeds while the ledger account has not consumed any nonce.
+    pub fn unpack_legacy(program_id: &Pubkey, input: &[u8]) -> Result<Self, BankError> {
+        if !LegacyCommandInstruction::matches(input) {
+            return Err(BankError::InvalidInstruction);
+        }
+        let legacy: LegacyCommandInstruction =
+            deserialize(input).map_err(|_| BankError::InvalidInstruction)?;
+        if legacy.program_id != *program_id {
+            return Err(BankError::ProgramIdMismatch);
+        }
+        Ok(CommandInstruction {
+            command: legacy.command.into(),
+            nonce: 0,
+        })
+    }
+}
+
+/// Commands as they existed before `CommandInstruction::pack`, the only ones older
+/// clients send in the legacy encoding.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyCommand {
+    Deposit { amount: u64 },
+    Withdraw { amount: u64 },
+    CheckBalance,
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(command: LegacyCommand) -> Self {
+        match command {
+            LegacyCommand::Deposit { amount } => Command::Deposit { amount },
+            LegacyCommand::Withdraw { amount } => Command::Withdraw { amount },
+            LegacyCommand::CheckBalance => Command::CheckBalance,
+        }
+    }
+}
+
+/// Instruction data as encoded before `CommandInstruction::pack`, still accepted while
+/// clients move to the compact encoding: the bincode encoding of the command, a
+/// little-endian `u32` variant index then its amount, followed by the program id.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LegacyCommandInstruction {
+    pub command: LegacyCommand,
+    pub program_id: Pubkey,
+}
+
+impl LegacyCommandInstruction {
+    /// Whether `input` has the variant index and size of a legacy instruction.
+    ///
+    /// The compact encodings starting with the same byte are shorter, so the two
+    /// encodings never overlap and compact data is never decoded as legacy.
+    pub fn matches(input: &[u8]) -> bool {
+        match input {
+            [0 | 1, 0, 0, 0, ..] => input.len() == 4 + 8 + 32,
+            [2, 0, 0, 0, ..] => input.len() == 4 + 32,
+            _ => false,
+        }
+    }
+}
+
+/// Several commands applied in order to the same ledger account, see `process_batch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchInstruction {
+    pub commands: Vec<Command>,
+    /// Sequence number of the ledger account, consumed once by the whole batch unless
+    /// none of its commands needs it
+    pub nonce: u64,
+}
+
+impl BatchInstruction {
+    /// First byte of a batch, a tag no `Command` uses.
+    pub const TAG: u8 = 19;
+
+    /// Batch of `commands` at the ledger's current `nonce`.
+    pub fn new(commands: Vec<Command>, nonce: u64) -> Self {
+        BatchInstruction { commands, nonce }
+    }
+
+    /// Encode the batch: `TAG`, the number of commands as a byte, each command as encoded
+    /// by `Command::pack_into`, then the nonce in little-endian.
+    ///
+    /// Fails unless the batch holds from 1 to 255 commands.
+    pub fn pack(&self) -> Result<Vec<u8>, BankError> {
+        let count = u8::try_from(self.commands.len()).map_err(|_| BankError::InvalidInstruction)?;
+        if count == 0 {
+            return Err(BankError::InvalidInstruction);
+        }
+        let mut data = vec![Self::TAG, count];
+        for command in &self.commands {
+            command.pack_into(&mut data);
+        }
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        Ok(data)
+    }
+
+    /// Decode a batch produced by `pack`. A batch holds at least one command.
+    pub fn unpack(input: &[u8]) -> Result<Self, BankError> {
+        let mut rest = input;
+        if unpack_u8(&mut rest)? != Self::TAG {
+            return Err(BankError::InvalidInstruction);
+        }
+        let count = unpack_u8(&mut rest)?;
+        if count == 0 {
+            return Err(BankError::InvalidInstruction);
+        }
+        let commands = (0..count)
+            .map(|_| Command::unpack_from(&mut rest))
+            .collect::<Result<Vec<_>, _>>()?;
+        let nonce = unpack_u64(&mut rest)?;
+        if !rest.is_empty() {
+            return Err(BankError::InvalidInstruction);
+        }
+        Ok(BatchInstruction { commands, nonce })
+    }
+}
+
+fn unpack_u8(input: &mut &[u8]) -> Result<u8, BankError> {
+    let (&byte, rest) = input.split_first().ok_or(BankError::InvalidInstruction)?;
+    *input = rest;
+    Ok(byte)
+}
+
+fn unpack_bool(input: &mut &[u8]) -> Result<bool, BankError> {
+    match unpack_u8(input)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(BankError::InvalidInstruction),
+    }
+}
+
+fn unpack_u16(input: &mut &[u8]) -> Result<u16, BankError> {
+    if input.len() < 2 {
+        return Err(BankError::InvalidInstruction);
+    }
+    let (bytes, rest) = input.split_at(2);
+    *input = rest;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn unpack_pubkey(input: &mut &[u8]) -> Result<Pubkey, BankError> {
+    if input.len() < 32 {
+        return Err(BankError::InvalidInstruction);
+    }
+    let (bytes, rest) = input.split_at(32);
+    *input = rest;
+    Ok(Pubkey::new_from_array(bytes.try_into().unwrap()))
+}
+
+fn unpack_u64(input: &mut &[u8]) -> Result<u64, BankError> {
+    if input.len() < 8 {
+        return Err(BankError::InvalidInstruction);
+    }
+    let (bytes, rest) = input.split_at(8);
+    *input = rest;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::serialize;
+    use proptest::{collection::vec, prelude::*};
+    use solana_program_test::*;
+    use solana_sdk::{
+        account::Account,
+        compute_budget::ComputeBudgetInstruction,
+        hash::Hash,
+        instruction::{Instruction, InstructionError},
+        pubkey::Pubkey,
+        signature::Keypair,
+        signer::Signer,
+        transaction::{Transaction, TransactionError},
+    };
+
+    /// Add a ledger account with the given balance and its funded vault.
+    fn add_ledger(
+        program_test: &mut ProgramTest,
+        program_id: Pubkey,
+        authority: Pubkey,
+        balance: u64,
+    ) -> (Pubkey, Pubkey) {
+        let ledger = Pubkey::new_unique();
+        let (vault, vault_bump) = find_vault_address(&program_id, &ledger);
+        let mut data = vec![0; Data::LEN];
+        Data {
+            balance,
+            ..Data::new(authority, vault_bump)
+        }
+        .store(&mut data)
+        .unwrap();
+        program_test.add_account(
+            ledger,
+            Account {
+                lamports: 1_000_000_000,
+                data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            vault,
+            Account {
+                lamports: Rent::default().minimum_balance(0) + balance,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        (ledger, vault)
+    }
+
+    /// Create a mint with 6 decimals and a token account of `owner` holding `amount` tokens.
+    async fn create_mint_and_token_account(
+        banks_client: &BanksClient,
+        payer: &Keypair,
+        owner: &Pubkey,
+        amount: u64,
+    ) -> (Pubkey, Pubkey) {
+        let mint = Keypair::new();
+        let token_account = Keypair::new();
+        let rent = banks_client.get_rent().await.unwrap();
+        let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                system_instruction::create_account(
+                    &payer.pubkey(),
+                    &mint.pubkey(),
+                    rent.minimum_balance(Mint::LEN),
+                    Mint::LEN as u64,
+                    &spl_token::id(),
+                ),
+                spl_token::instruction::initialize_mint2(
+                    &spl_token::id(),
+                    &mint.pubkey(),
+                    &payer.pubkey(),
+                    None,
+                    6,
+                )
+                .unwrap(),
+                system_instruction::create_account(
+                    &payer.pubkey(),
+                    &token_account.pubkey(),
+                    rent.minimum_balance(TokenAccount::LEN),
+                    TokenAccount::LEN as u64,
+                    &spl_token::id(),
+                ),
+                spl_token::instruction::initialize_account3(
+                    &spl_token::id(),
+                    &token_account.pubkey(),
+                    &mint.pubkey(),
+                    owner,
+                )
+                .unwrap(),
+                spl_token::instruction::mint_to(
+                    &spl_token::id(),
+                    &mint.pubkey(),
+                    &token_account.pubkey(),
+                    &payer.pubkey(),
+                    &[],
+                    amount,
+                )
+                .unwrap(),
+            ],
+            Some(&payer.pubkey()),
+            &[payer, &mint, &token_account],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+        (mint.pubkey(), token_account.pubkey())
+    }
+
+    async fn token_amount(banks_client: &BanksClient, token_account: Pubkey) -> u64 {
+        let account = banks_client
+            .get_account(token_account)
+            .await
+            .unwrap()
+            .unwrap();
+        TokenAccount::unpack(&account.data).unwrap().amount
+    }
+
+    /// Process `instruction` in a transaction paid by `payer` and signed by `signers`.
+    async fn process(
+        banks_client: &BanksClient,
+        payer: &Keypair,
+        signers: &[&Keypair],
+        instruction: Instruction,
+        recent_blockhash: Hash,
+    ) -> Result<(), TransactionError> {
+        let mut keypairs = vec![payer];
+        keypairs.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &keypairs,
+            recent_blockhash,
+        );
+        banks_client
+            .process_transaction(transaction)
+            .await
+            .map_err(|err| err.unwrap())
+    }
+
+    /// Move to a later slot and override the time the program reads from the clock.
+    async fn warp_to(context: &mut ProgramTestContext, slot: u64, unix_timestamp: i64) {
+        context.warp_to_slot(slot).unwrap();
+        let clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+        context.set_sysvar(&Clock {
+            unix_timestamp,
+            ..clock
+        });
+        context.last_blockhash = context.get_new_latest_blockhash().await.unwrap();
+    }
+
+    /// Withdraw from `ledger` in a transaction of its own.
+    async fn withdraw(
+        context: &mut ProgramTestContext,
+        program_id: Pubkey,
+        ledger: Pubkey,
+        authority: &Keypair,
+        amount: u64,
+        nonce: u64,
+    ) -> Result<(), TransactionError> {
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::withdraw(
+                &program_id,
+                &ledger,
+                &authority.pubkey(),
+                amount,
+                nonce,
+            )],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, authority],
+            context.last_blockhash,
+        );
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .map_err(|err| err.unwrap())
+    }
+
+    /// Add a system account funded with 1 SOL.
+    fn add_wallet(program_test: &mut ProgramTest) -> Keypair {
+        let wallet = Keypair::new();
+        program_test.add_account(
+            wallet.pubkey(),
+            Account {
+                lamports: 1_000_000_000,
+                ..Account::default()
+            },
+        );
+        wallet
+    }
+
+    #[tokio::test]
+    async fn test_deposit() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        // Create an account to hold the data
+        let (user_account, vault) =
+            add_ledger(&mut program_test, program_id, Pubkey::new_unique(), 0);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+        let payer_lamports = banks_client.get_balance(payer.pubkey()).await.unwrap();
+
+        // Deposit 100 lamports, creating the transaction to call the program
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::deposit(
+                &program_id,
+                &user_account,
+                &payer.pubkey(),
+                100,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+
+        // Process the transaction with error handling
+        match banks_client.process_transaction(transaction).await {
+            Ok(_) => {
+                // Transaction succeeded, check the balance
+                let account_data = banks_client
+                    .get_account(user_account)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let data = Data::load(&account_data.data).unwrap();
+                assert_eq!(data.balance, 100);
+            }
+            Err(e) => {
+                // Handle the error appropriately
+                eprintln!("Transaction failed: {:?}", e);
+                panic!("Transaction failed: {:?}", e); // You can choose to panic or handle it differently
+            }
+        }
+
+        // The lamports moved from the depositor into the vault
+        let vault_lamports = banks_client.get_balance(vault).await.unwrap();
+        assert_eq!(vault_lamports, Rent::default().minimum_balance(0) + 100);
+        let fee = 5000;
+        assert_eq!(
+            banks_client.get_balance(payer.pubkey()).await.unwrap(),
+            payer_lamports - 100 - fee
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdraw() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        // Create an account to hold the data
+        let authority = add_wallet(&mut program_test);
+        let (user_account, vault) =
+            add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // Withdraw 50 lamports
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::withdraw(
+                &program_id,
+                &user_account,
+                &authority.pubkey(),
+                50,
+                0,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // Check the balance
+        let account_data = banks_client
+            .get_account(user_account)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account_data.data).unwrap();
+        assert_eq!(data.balance, 50);
+
+        // The vault still backs the remaining balance and the authority got the rest
+        let vault_lamports = banks_client.get_balance(vault).await.unwrap();
+        assert_eq!(vault_lamports, Rent::default().minimum_balance(0) + 50);
+        assert_eq!(
+            banks_client.get_balance(authority.pubkey()).await.unwrap(),
+            1_000_000_050
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_balance() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        // Create an account to hold the data
+        let (user_account, _) =
+            add_ledger(&mut program_test, program_id, Pubkey::new_unique(), 150);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // Check balance, with the ledger passed read-only
+        let check_balance = instruction::check_balance(&program_id, &user_account);
+        assert!(!check_balance.accounts[0].is_writable);
+        let transaction = Transaction::new_signed_with_payer(
+            &[check_balance],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        let before = banks_client
+            .get_account(user_account)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Simulating is enough to read the balance from the return data
+        let simulation = banks_client
+            .simulate_transaction(transaction.clone())
+            .await
+            .unwrap();
+        assert!(simulation.result.unwrap().is_ok());
+        let return_data = simulation.simulation_details.unwrap().return_data.unwrap();
+        assert_eq!(return_data.program_id, program_id);
+        assert_eq!(instruction::unpack_balance(&return_data.data), Some(150));
+
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // The balance should still be 150 and the account untouched
+        let account_data = banks_client
+            .get_account(user_account)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account_data, before);
+        let data = Data::load(&account_data.data).unwrap();
+        assert_eq!(data.balance, 150);
+
+        // Return data may come back without its trailing zero bytes
+        assert_eq!(instruction::unpack_balance(&[150]), Some(150));
+        assert_eq!(instruction::unpack_balance(&[]), Some(0));
+        assert_eq!(instruction::unpack_balance(&[0; 9]), None);
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_funds() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        // Create an account with a balance of 50
+        let authority = Keypair::new();
+        let (user_account, _) = add_ledger(&mut program_test, program_id, authority.pubkey(), 50);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // Attempt to withdraw 100 lamports (should fail)
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::withdraw(
+                &program_id,
+                &user_account,
+                &authority.pubkey(),
+                100,
+                0,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+
+        let result = banks_client.process_transaction(transaction).await;
+        assert!(result.is_err());
+
+        // Clients can decode the typed error from the transaction error
+        match result.unwrap_err().unwrap() {
+            TransactionError::InstructionError(_, error) => assert_eq!(
+                BankError::from_instruction_error(&error),
+                Some(BankError::InsufficientFunds)
+            ),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_initialize() {
+        let program_id = Pubkey::new_unique();
+        let program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // Lamports sent to the addresses beforehand don't block the creation
+        let (ledger, _) = find_ledger_address(&program_id, &payer.pubkey());
+        let (vault, _) = find_vault_address(&program_id, &ledger);
+        let rent = banks_client.get_rent().await.unwrap();
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                system_instruction::transfer(&payer.pubkey(), &ledger, rent.minimum_balance(0)),
+                system_instruction::transfer(&payer.pubkey(), &vault, rent.minimum_balance(0)),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // Create the ledger account of the payer
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::initialize(&program_id, &payer.pubkey())],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // The accounts are rent-exempt, owned by the program and the ledger is empty
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        assert_eq!(account.owner, program_id);
+        assert_eq!(account.data.len(), Data::LEN);
+        assert!(rent.is_exempt(account.lamports, Data::LEN));
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 0);
+        assert_eq!(data.authority, payer.pubkey());
+        let vault_account = banks_client.get_account(vault).await.unwrap().unwrap();
+        assert_eq!(vault_account.owner, program_id);
+        assert_eq!(vault_account.lamports, rent.minimum_balance(0));
+
+        // Deposit into the new account
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::deposit(
+                &program_id,
+                &ledger,
+                &payer.pubkey(),
+                25,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 25);
+
+        // Initializing twice fails
+        let recent_blockhash = banks_client
+            .get_new_latest_blockhash(&recent_blockhash)
+            .await
+            .unwrap();
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::initialize(&program_id, &payer.pubkey())],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert!(banks_client.process_transaction(transaction).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let program_id = Pubkey::new_unique();
+        let program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // The history length is checked before any account is created
+        let (ledger, _) = find_ledger_address(&program_id, &payer.pubkey());
+        for capacity in [0, MAX_HISTORY_LEN + 1] {
+            let result = process(
+                &banks_client,
+                &payer,
+                &[],
+                instruction::initialize_with_history(&program_id, &payer.pubkey(), capacity),
+                recent_blockhash,
+            )
+            .await;
+            assert_eq!(
+                result,
+                Err(TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(BankError::InvalidHistoryLength.code())
+                ))
+            );
+        }
+        assert_eq!(banks_client.get_account(ledger).await.unwrap(), None);
+
+        // The ledger account grows to hold the whole history up front
+        process(
+            &banks_client,
+            &payer,
+            &[],
+            instruction::initialize_with_history(&program_id, &payer.pubkey(), 2),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let len = Data::LEN + 2 * HistoryEntry::LEN;
+        assert_eq!(account.data.len(), len);
+        assert!(Rent::default().is_exempt(account.lamports, len));
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.history.capacity, 2);
+        assert!(data.history.entries.is_empty());
+
+        // Three operations, so the first is overwritten
+        for (index, instruction) in [
+            instruction::deposit(&program_id, &ledger, &payer.pubkey(), 25),
+            instruction::withdraw(&program_id, &ledger, &payer.pubkey(), 10, 0),
+            instruction::deposit(&program_id, &ledger, &payer.pubkey(), 5),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            process(&banks_client, &payer, &[], instruction, recent_blockhash)
+                .await
+                .unwrap_or_else(|err| panic!("operation {index}: {err}"));
+        }
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        assert_eq!(account.data.len(), len);
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 20);
+        let history: Vec<(Operation, u64, Pubkey)> = data
+            .history
+            .iter()
+            .map(|entry| (entry.kind, entry.amount, entry.counterparty))
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (Operation::Withdraw, 10, payer.pubkey()),
+                (Operation::Deposit, 5, payer.pubkey()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_deposit_and_withdraw() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = Keypair::new();
+        let (ledger, _) = add_ledger(&mut program_test, program_id, authority.pubkey(), 0);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // Two mints, e.g. USDC and another token, each with a funded payer account
+        let (usdc, payer_usdc) =
+            create_mint_and_token_account(&banks_client, &payer, &payer.pubkey(), 1_000).await;
+        let (other, payer_other) =
+            create_mint_and_token_account(&banks_client, &payer, &payer.pubkey(), 500).await;
+        let (usdc_vault, _) = find_token_vault_address(&program_id, &ledger, &usdc);
+        let (other_vault, _) = find_token_vault_address(&program_id, &ledger, &other);
+
+        // Lamports sent to a token vault address beforehand don't block its creation
+        let transaction = Transaction::new_signed_with_payer(
+            &[system_instruction::transfer(
+                &payer.pubkey(),
+                &other_vault,
+                Rent::default().minimum_balance(0),
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let deposit = |mint: Pubkey, source: Pubkey, amount: u64| {
+            instruction::token_deposit(
+                &program_id,
+                &ledger,
+                &mint,
+                &payer.pubkey(),
+                &source,
+                amount,
+            )
+        };
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                deposit(usdc, payer_usdc, 400),
+                deposit(other, payer_other, 500),
+                deposit(usdc, payer_usdc, 100),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // Balances are tracked per mint and backed by the token vaults
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.token_balance(&usdc), 500);
+        assert_eq!(data.token_balance(&other), 500);
+        assert_eq!(data.balance, 0);
+        assert_eq!(account.data.len(), data.size());
+        assert!(Rent::default().is_exempt(account.lamports, account.data.len()));
+        assert_eq!(token_amount(&banks_client, usdc_vault).await, 500);
+        assert_eq!(token_amount(&banks_client, other_vault).await, 500);
+        assert_eq!(token_amount(&banks_client, payer_usdc).await, 500);
+
+        // Withdraw USDC back to the payer's token account
+        let withdraw = |signer: Pubkey, amount: u64, nonce: u64| {
+            instruction::token_withdraw(
+                &program_id,
+                &ledger,
+                &usdc,
+                &signer,
+                &payer_usdc,
+                amount,
+                nonce,
+            )
+        };
+        let transaction = Transaction::new_signed_with_payer(
+            &[withdraw(authority.pubkey(), 150, 0)],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.token_balance(&usdc), 350);
+        assert_eq!(data.token_balance(&other), 500);
+        assert_eq!(token_amount(&banks_client, usdc_vault).await, 350);
+        assert_eq!(token_amount(&banks_client, payer_usdc).await, 650);
+
+        // Only the authority can withdraw, and never more than the mint's balance
+        let transaction = Transaction::new_signed_with_payer(
+            &[withdraw(payer.pubkey(), 10, 1)],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert!(banks_client.process_transaction(transaction).await.is_err());
+        let transaction = Transaction::new_signed_with_payer(
+            &[withdraw(authority.pubkey(), 351, 1)],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        assert!(banks_client.process_transaction(transaction).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = Keypair::new();
+        let (source, source_vault) =
+            add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
+        let (destination, destination_vault) =
+            add_ledger(&mut program_test, program_id, Pubkey::new_unique(), 10);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let transfer = |is_signer: bool, amount: u64, nonce: u64| {
+            let mut instruction = instruction::transfer(
+                &program_id,
+                &source,
+                &authority.pubkey(),
+                &destination,
+                amount,
+                nonce,
+            );
+            instruction.accounts[2].is_signer = is_signer;
+            instruction
+        };
+
+        // Move 30 from source to destination in a single instruction
+        let transaction = Transaction::new_signed_with_payer(
+            &[transfer(true, 30, 0)],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let rent_exempt = Rent::default().minimum_balance(0);
+        let account = banks_client.get_account(source).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 70);
+        assert_eq!(
+            banks_client.get_balance(source_vault).await.unwrap(),
+            rent_exempt + 70
+        );
+        let account = banks_client
+            .get_account(destination)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 40);
+        assert_eq!(
+            banks_client.get_balance(destination_vault).await.unwrap(),
+            rent_exempt + 40
+        );
+
+        // Unsigned transfers and overdrafts are rejected
+        let transaction = Transaction::new_signed_with_payer(
+            &[transfer(false, 10, 1)],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert!(banks_client.process_transaction(transaction).await.is_err());
+        let transaction = Transaction::new_signed_with_payer(
+            &[transfer(true, 71, 1)],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        assert!(banks_client.process_transaction(transaction).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_escrow() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let recipient_authority = add_wallet(&mut program_test);
+        let arbiter = Keypair::new();
+        let (source, source_vault) =
+            add_ledger(&mut program_test, program_id, authority.pubkey(), 1_000);
+        let (recipient, recipient_vault) = add_ledger(
+            &mut program_test,
+            program_id,
+            recipient_authority.pubkey(),
+            0,
+        );
+
+        let mut context = program_test.start_with_context().await;
+        warp_to(&mut context, 10, 1_000).await;
+
+        let custom_error = |error: BankError| {
+            TransactionError::InstructionError(0, InstructionError::Custom(error.code()))
+        };
+        let terms = EscrowTerms {
+            recipient,
+            arbiter: arbiter.pubkey(),
+            amount: 300,
+            expiry_slot: 100,
+            recipient_can_release: false,
+        };
+        let balance =
+            |account: Option<Account>| Data::load(&account.unwrap().data).unwrap().balance;
+        let open_escrows =
+            |account: Option<Account>| Data::load(&account.unwrap().data).unwrap().open_escrows;
+        let rent_exempt = Rent::default().minimum_balance(0);
+        let escrow_rent = Rent::default().minimum_balance(Escrow::LEN);
+
+        // The escrow must expire in the future and pay another ledger
+        for (terms, error) in [
+            (
+                EscrowTerms {
+                    expiry_slot: 10,
+                    ..terms
+                },
+                BankError::InvalidEscrow,
+            ),
+            (
+                EscrowTerms {
+                    recipient: source,
+                    ..terms
+                },
+                BankError::SelfTransfer,
+            ),
+        ] {
+            let escrow = Keypair::new();
+            let create = instruction::create_escrow(
+                &program_id,
+                &source,
+                &authority.pubkey(),
+                &escrow.pubkey(),
+                &context.payer.pubkey(),
+                terms,
+                0,
+            );
+            let result = process(
+                &context.banks_client,
+                &context.payer,
+                &[&authority, &escrow],
+                create,
+                context.last_blockhash,
+            )
+            .await;
+            assert_eq!(result, Err(custom_error(error)));
+        }
+
+        // Three escrows: released by the arbiter, released by the recipient, refunded
+        let escrows = [Keypair::new(), Keypair::new(), Keypair::new()];
+        let recipient_release = EscrowTerms {
+            amount: 200,
+            recipient_can_release: true,
+            ..terms
+        };
+        let refunded = EscrowTerms {
+            amount: 100,
+            ..terms
+        };
+        for (nonce, (escrow, terms)) in escrows
+            .iter()
+            .zip([terms, recipient_release, refunded])
+            .enumerate()
+        {
+            let create = instruction::create_escrow(
+                &program_id,
+                &source,
+                &authority.pubkey(),
+                &escrow.pubkey(),
+                &context.payer.pubkey(),
+                terms,
+                nonce as u64,
+            );
+            process(
+                &context.banks_client,
+                &context.payer,
+                &[&authority, escrow],
+                create,
+                context.last_blockhash,
+            )
+            .await
+            .unwrap();
+        }
+        let account = context
+            .banks_client
+            .get_account(escrows[0].pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.owner, program_id);
+        assert_eq!(account.lamports, escrow_rent + 300);
+        assert_eq!(
+            Escrow::load(&account.data).unwrap(),
+            Escrow {
+                source,
+                payer: context.payer.pubkey(),
+                terms,
+                counted: true,
+            }
+        );
+        let account = context.banks_client.get_account(source).await.unwrap();
+        assert_eq!(balance(account.clone()), 400);
+        assert_eq!(open_escrows(account), 3);
+        assert_eq!(
+            context
+                .banks_client
+                .get_balance(source_vault)
+                .await
+                .unwrap(),
+            rent_exempt + 400
+        );
+
+        // Only the arbiter releases the first escrow, and not before expiry it is refunded
+        let release = |escrow: &Keypair, releaser: &Keypair| {
+            instruction::release_escrow(
+                &program_id,
+                &escrow.pubkey(),
+                &source,
+                &recipient,
+                &context.payer.pubkey(),
+                &releaser.pubkey(),
+            )
+        };
+        let refund = |escrow: &Keypair| {
+            instruction::refund_escrow(
+                &program_id,
+                &escrow.pubkey(),
+                &source,
+                &context.payer.pubkey(),
+            )
+        };
+        let result = process(
+            &context.banks_client,
+            &context.payer,
+            &[&recipient_authority],
+            release(&escrows[0], &recipient_authority),
+            context.last_blockhash,
+        )
+        .await;
+        assert_eq!(result, Err(custom_error(BankError::NotEscrowReleaser)));
+        let result = process(
+            &context.banks_client,
+            &context.payer,
+            &[],
+            refund(&escrows[0]),
+            context.last_blockhash,
+        )
+        .await;
+        assert_eq!(result, Err(custom_error(BankError::EscrowNotExpired)));
+        for (escrow, releaser) in [(&escrows[0], &arbiter), (&escrows[1], &recipient_authority)] {
+            process(
+                &context.banks_client,
+                &context.payer,
+                &[releaser],
+                release(escrow, releaser),
+                context.last_blockhash,
+            )
+            .await
+            .unwrap();
+            // The rent went back to the payer with the account
+            assert_eq!(
+                context
+                    .banks_client
+                    .get_account(escrow.pubkey())
+                    .await
+                    .unwrap(),
+                None
+            );
+        }
+        let account = context.banks_client.get_account(recipient).await.unwrap();
+        assert_eq!(balance(account), 500);
+        let account = context.banks_client.get_account(source).await.unwrap();
+        assert_eq!(open_escrows(account), 1);
+        assert_eq!(
+            context
+                .banks_client
+                .get_balance(recipient_vault)
+                .await
+                .unwrap(),
+            rent_exempt + 500
+        );
+
+        // After expiry the arbiter can't release, but anyone can refund
+        warp_to(&mut context, 100, 2_000).await;
+        let release = instruction::release_escrow(
+            &program_id,
+            &escrows[2].pubkey(),
+            &source,
+            &recipient,
+            &context.payer.pubkey(),
+            &arbiter.pubkey(),
+        );
+        let result = process(
+            &context.banks_client,
+            &context.payer,
+            &[&arbiter],
+            release,
+            context.last_blockhash,
+        )
+        .await;
+        assert_eq!(result, Err(custom_error(BankError::EscrowExpired)));
+
+        // The refund pays back into the source, so it can't be closed before
+        let close = instruction::close(
+            &program_id,
+            &source,
+            &authority.pubkey(),
+            &context.payer.pubkey(),
+            &[],
+            3,
+        );
+        let result = process(
+            &context.banks_client,
+            &context.payer,
+            &[&authority],
+            close.clone(),
+            context.last_blockhash,
+        )
+        .await;
+        assert_eq!(result, Err(custom_error(BankError::EscrowsOpen)));
+        let refund = instruction::refund_escrow(
+            &program_id,
+            &escrows[2].pubkey(),
+            &source,
+            &context.payer.pubkey(),
+        );
+        process(
+            &context.banks_client,
+            &context.payer,
+            &[],
+            refund.clone(),
+            context.last_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = context.banks_client.get_account(source).await.unwrap();
+        assert_eq!(balance(account.clone()), 500);
+        assert_eq!(open_escrows(account), 0);
+        assert_eq!(
+            context
+                .banks_client
+                .get_balance(source_vault)
+                .await
+                .unwrap(),
+            rent_exempt + 500
+        );
+
+        // The wiped escrow pays out only once
+        context.last_blockhash = context.get_new_latest_blockhash().await.unwrap();
+        let result = process(
+            &context.banks_client,
+            &context.payer,
+            &[],
+            refund,
+            context.last_blockhash,
+        )
+        .await;
+        assert_eq!(result, Err(custom_error(BankError::InvalidAccountOwner)));
+
+        // With every escrow paid out the source closes
+        process(
+            &context.banks_client,
+            &context.payer,
+            &[&authority],
+            close,
+            context.last_blockhash,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            context.banks_client.get_account(source).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_escrow_created_before_counting() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let escrow_payer = add_wallet(&mut program_test);
+        let arbiter = Keypair::new();
+        let (recipient, _) = add_ledger(&mut program_test, program_id, Pubkey::new_unique(), 0);
+
+        // A layout version 6 source ledger, without `open_escrows`
+        let source = Pubkey::new_unique();
+        let (source_vault, vault_bump) = find_vault_address(&program_id, &source);
+        let mut data = vec![0; Data::LEN];
+        Data {
+            balance: 700,
+            ..Data::new(authority.pubkey(), vault_bump)
+        }
+        .store(&mut data)
+        .unwrap();
+        let (offset, len) = LAYOUT_CHANGES[usize::from(LAYOUT_VERSION) - 3];
+        data.drain(Data::HEADER_LEN + offset..Data::HEADER_LEN + offset + len);
+        data[8] = 6;
+        program_test.add_account(
+            source,
+            Account {
+                lamports: 1_000_000_000,
+                data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            source_vault,
+            Account {
+                lamports: Rent::default().minimum_balance(0) + 700,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+
+        // An escrow it created before escrows were counted
+        let terms = EscrowTerms {
+            recipient,
+            arbiter: arbiter.pubkey(),
+            amount: 300,
+            expiry_slot: 100,
+            recipient_can_release: false,
+        };
+        let old_escrow = Pubkey::new_unique();
+        let mut data = ESCROW_DISCRIMINATOR.to_vec();
+        data.extend(serialize(&(source, escrow_payer.pubkey(), terms)).unwrap());
+        assert_eq!(data.len(), Escrow::UNCOUNTED_LEN);
+        assert!(!Escrow::load(&data).unwrap().counted);
+        program_test.add_account(
+            old_escrow,
+            Account {
+                lamports: Rent::default().minimum_balance(data.len()) + 300,
+                data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+
+        let mut context = program_test.start_with_context().await;
+        warp_to(&mut context, 10, 1_000).await;
+
+        let custom_error = |error: BankError| {
+            TransactionError::InstructionError(0, InstructionError::Custom(error.code()))
+        };
+        let open_escrows =
+            |account: Option<Account>| Data::load(&account.unwrap().data).unwrap().open_escrows;
+
+        // Migrated, the ledger counts none of its escrows
+        process(
+            &context.banks_client,
+            &context.payer,
+            &[],
+            instruction::upgrade(&program_id, &source, &context.payer.pubkey()),
+            context.last_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = context.banks_client.get_account(source).await.unwrap();
+        assert_eq!(open_escrows(account), 0);
+
+        let new_escrow = Keypair::new();
+        process(
+            &context.banks_client,
+            &context.payer,
+            &[&authority, &new_escrow],
+            instruction::create_escrow(
+                &program_id,
+                &source,
+                &authority.pubkey(),
+                &new_escrow.pubkey(),
+                &context.payer.pubkey(),
+                terms,
+                0,
+            ),
+            context.last_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = context.banks_client.get_account(source).await.unwrap();
+        assert_eq!(open_escrows(account), 1);
+
+        // Releasing the old escrow leaves the count of the new one alone
+        process(
+            &context.banks_client,
+            &context.payer,
+            &[&arbiter],
+            instruction::release_escrow(
+                &program_id,
+                &old_escrow,
+                &source,
+                &recipient,
+                &escrow_payer.pubkey(),
+                &arbiter.pubkey(),
+            ),
+            context.last_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = context.banks_client.get_account(source).await.unwrap();
+        assert_eq!(open_escrows(account), 1);
+        let close = instruction::close(
+            &program_id,
+            &source,
+            &authority.pubkey(),
+            &context.payer.pubkey(),
+            &[],
+            1,
+        );
+        let result = process(
+            &context.banks_client,
+            &context.payer,
+            &[&authority],
+            close.clone(),
+            context.last_blockhash,
+        )
+        .await;
+        assert_eq!(result, Err(custom_error(BankError::EscrowsOpen)));
+
+        // Once the new escrow is refunded the ledger closes
+        warp_to(&mut context, 100, 2_000).await;
+        process(
+            &context.banks_client,
+            &context.payer,
+            &[],
+            instruction::refund_escrow(
+                &program_id,
+                &new_escrow.pubkey(),
+                &source,
+                &context.payer.pubkey(),
+            ),
+            context.last_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = context.banks_client.get_account(source).await.unwrap();
+        assert_eq!(open_escrows(account), 0);
+        process(
+            &context.banks_client,
+            &context.payer,
+            &[&authority],
+            close,
+            context.last_blockhash,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            context.banks_client.get_account(source).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_v1_account() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        // A version 1 account as created before the header existed
+        let legacy_account = Keypair::new();
+        program_test.add_account(
+            legacy_account.pubkey(),
+            Account {
+                lamports: 1_000_000_000,
+                data: serialize(&LegacyData {
+                    number: 7,
+                    balance: 150,
+                })
+                .unwrap(),
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        let (vault, _) = find_vault_address(&program_id, &legacy_account.pubkey());
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // Commands other than Migrate reject the old layout
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::check_balance(
+                &program_id,
+                &legacy_account.pubkey(),
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(BankError::AccountNeedsMigration.code())
+            )
+        );
+
+        // Lamports sent to the vault address beforehand don't block the migration
+        let transaction = Transaction::new_signed_with_payer(
+            &[system_instruction::transfer(
+                &payer.pubkey(),
+                &vault,
+                Rent::default().minimum_balance(0),
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let migrate = |ledger_signs: bool| {
+            let mut instruction =
+                instruction::migrate(&program_id, &legacy_account.pubkey(), &payer.pubkey());
+            instruction.accounts[0].is_signer = ledger_signs;
+            instruction
+        };
+
+        // The legacy account's keypair must sign
+        let transaction = Transaction::new_signed_with_payer(
+            &[migrate(false)],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert!(banks_client.process_transaction(transaction).await.is_err());
+
+        let transaction = Transaction::new_signed_with_payer(
+            &[migrate(true)],
+            Some(&payer.pubkey()),
+            &[&payer, &legacy_account],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // The account grew to the version 2 layout and kept its state
+        let account = banks_client
+            .get_account(legacy_account.pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.data.len(), Data::LEN);
+        assert_eq!(Data::version(&account.data), Ok(LAYOUT_VERSION));
+        assert!(Rent::default().is_exempt(account.lamports, account.data.len()));
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.number, 7);
+        assert_eq!(data.balance, 150);
+        assert_eq!(data.authority, payer.pubkey());
+        assert_eq!(
+            banks_client.get_balance(vault).await.unwrap(),
+            Rent::default().minimum_balance(0) + 150
+        );
+
+        // Migrating again fails
+        let recent_blockhash = banks_client
+            .get_new_latest_blockhash(&recent_blockhash)
+            .await
+            .unwrap();
+        let transaction = Transaction::new_signed_with_payer(
+            &[migrate(true)],
+            Some(&payer.pubkey()),
+            &[&payer, &legacy_account],
+            recent_blockhash,
+        );
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(BankError::AlreadyMigrated.code())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_older_layouts() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        // An account of each layout version since the header, with its data once migrated
+        let authority = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let tokens = vec![TokenBalance {
+            mint: Pubkey::new_unique(),
+            amount: 9,
+        }];
+        let vesting = VestingSchedule::unlock_at(5);
+        let limit = WithdrawalLimit {
+            amount: 30,
+            window: 60,
+        };
+        let history = History {
+            capacity: 2,
+            next: 1,
+            entries: vec![HistoryEntry {
+                kind: Operation::Deposit,
+                amount: 80,
+                slot: 3,
+                counterparty: authority,
+            }],
+        };
+        let mut accounts = vec![];
+        for version in 2..LAYOUT_VERSION {
+            let ledger = Pubkey::new_unique();
+            let (_, vault_bump) = find_vault_address(&program_id, &ledger);
+            let fields = match version {
+                2 => serialize(&(4u64, 80u64, authority, vault_bump, &tokens)),
+                3 => serialize(&(4u64, 80u64, authority, vault_bump, delegate, 7u64, &tokens)),
+                4 => serialize(&(
+                    4u64, 80u64, authority, vault_bump, delegate, 7u64, 20u64, vesting, &tokens,
+                )),
+                5 => serialize(&(
+                    4u64,
+                    80u64,
+                    authority,
+                    vault_bump,
+                    delegate,
+                    7u64,
+                    20u64,
+                    vesting,
+                    limit,
+                    WithdrawalLimit::default(),
+                    0i64,
+                    7i64,
+                    20u64,
+                    &tokens,
+                )),
+                6 => serialize(&(
+                    4u64,
+                    80u64,
+                    authority,
+                    vault_bump,
+                    delegate,
+                    7u64,
+                    20u64,
+                    vesting,
+                    limit,
+                    WithdrawalLimit::default(),
+                    0i64,
+                    7i64,
+                    20u64,
+                    &history,
+                    &tokens,
+                )),
+                _ => unreachable!("a fixture for every older version"),
+            };
+            let mut data = ACCOUNT_DISCRIMINATOR.to_vec();
+            data.push(version);
+            data.extend(fields.unwrap());
+            program_test.add_account(
+                ledger,
+                Account {
+                    lamports: Rent::default().minimum_balance(data.len()),
+                    data,
+                    owner: program_id,
+                    ..Account::default()
+                },
+            );
+
+            let mut expected = Data {
+                number: 4,
+                balance: 80,
+                tokens: tokens.clone(),
+                ..Data::new(authority, vault_bump)
+            };
+            if version >= 3 {
+                expected.delegate = delegate;
+                expected.delegated_amount = 7;
+            }
+            if version >= 4 {
+                expected.locked_amount = 20;
+                expected.vesting = vesting;
+            }
+            if version >= 5 {
+                expected.withdrawal_limit = limit;
+                expected.window_start = 7;
+                expected.window_withdrawn = 20;
+            }
+            if version >= 6 {
+                expected.history = history.clone();
+            }
+            accounts.push((ledger, expected));
+        }
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        for (ledger, expected) in accounts {
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction::check_balance(&program_id, &ledger)],
+                Some(&payer.pubkey()),
+                &[&payer],
+                recent_blockhash,
+            );
+            assert_eq!(
+                banks_client
+                    .process_transaction(transaction)
+                    .await
+                    .unwrap_err()
+                    .unwrap(),
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(BankError::AccountNeedsMigration.code())
+                )
+            );
+
+            // The ledger is a program address, so any payer upgrades it without its signature
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction::upgrade(&program_id, &ledger, &payer.pubkey())],
+                Some(&payer.pubkey()),
+                &[&payer],
+                recent_blockhash,
+            );
+            banks_client.process_transaction(transaction).await.unwrap();
+
+            // The added fields are empty, and the account has room for the full history
+            let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+            assert_eq!(Data::version(&account.data), Ok(LAYOUT_VERSION));
+            assert!(Rent::default().is_exempt(account.lamports, account.data.len()));
+            let data = Data::load(&account.data).unwrap();
+            assert_eq!(account.data.len(), data.size());
+            assert_eq!(data, expected);
+            let (vault, _) = find_vault_address(&program_id, &ledger);
+            assert_eq!(banks_client.get_account(vault).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delegated_withdraw() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let delegate = add_wallet(&mut program_test);
+        let (ledger, vault) = add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let withdraw = |amount: u64, nonce: u64| {
+            instruction::withdraw(&program_id, &ledger, &delegate.pubkey(), amount, nonce)
+        };
+        let custom_error = |error: BankError| {
+            TransactionError::InstructionError(0, InstructionError::Custom(error.code()))
+        };
+
+        // Without an allowance the delegate is a stranger
+        let transaction = Transaction::new_signed_with_payer(
+            &[withdraw(10, 0)],
+            Some(&payer.pubkey()),
+            &[&payer, &delegate],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(BankError::WrongAuthority));
+
+        // Only the authority approves
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::approve(
+                &program_id,
+                &ledger,
+                &delegate.pubkey(),
+                &delegate.pubkey(),
+                60,
+                0,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer, &delegate],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(BankError::WrongAuthority));
+
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::approve(
+                    &program_id,
+                    &ledger,
+                    &authority.pubkey(),
+                    &delegate.pubkey(),
+                    60,
+                    0,
+                ),
+                withdraw(40, 1),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &authority, &delegate],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // The delegate received the lamports and its allowance went down
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 60);
+        assert_eq!(data.delegate, delegate.pubkey());
+        assert_eq!(data.delegated_amount, 20);
+        assert_eq!(
+            banks_client.get_balance(delegate.pubkey()).await.unwrap(),
+            1_000_000_040
+        );
+        assert_eq!(
+            banks_client.get_balance(vault).await.unwrap(),
+            Rent::default().minimum_balance(0) + 60
+        );
+
+        // The allowance caps delegated withdrawals but not the authority's
+        let transaction = Transaction::new_signed_with_payer(
+            &[withdraw(30, 2)],
+            Some(&payer.pubkey()),
+            &[&payer, &delegate],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(BankError::AllowanceExceeded));
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::withdraw(
+                &program_id,
+                &ledger,
+                &authority.pubkey(),
+                30,
+                2,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // After Revoke the delegate can't withdraw anything
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::revoke(&program_id, &ledger, &authority.pubkey(), 3),
+                withdraw(5, 4),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &authority, &delegate],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                1,
+                InstructionError::Custom(BankError::WrongAuthority.code())
+            )
+        );
+
+        // Using up the allowance removes the delegate
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::approve(
+                    &program_id,
+                    &ledger,
+                    &authority.pubkey(),
+                    &delegate.pubkey(),
+                    5,
+                    3,
+                ),
+                withdraw(5, 4),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &authority, &delegate],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 25);
+        assert_eq!(data.delegate, Pubkey::default());
+        assert_eq!(data.delegated_amount, 0);
+
+        // A multisig delegate withdraws with the threshold of its members
+        let members = [Keypair::new(), Keypair::new()];
+        let keys = members
+            .iter()
+            .map(|member| member.pubkey())
+            .collect::<Vec<_>>();
+        let multisig = Keypair::new();
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::create_multisig(
+                    &program_id,
+                    &multisig.pubkey(),
+                    &payer.pubkey(),
+                    &keys,
+                    2,
+                ),
+                instruction::approve(
+                    &program_id,
+                    &ledger,
+                    &authority.pubkey(),
+                    &multisig.pubkey(),
+                    10,
+                    5,
+                ),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &multisig, &authority],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+        let multisig_withdraw = |signers: &[Pubkey]| {
+            instruction::multisig_withdraw(
+                &program_id,
+                &ledger,
+                &multisig.pubkey(),
+                &delegate.pubkey(),
+                signers,
+                10,
+                6,
+            )
+        };
+        let transaction = Transaction::new_signed_with_payer(
+            &[multisig_withdraw(&keys[..1])],
+            Some(&payer.pubkey()),
+            &[&payer, &members[0]],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(BankError::NotEnoughSigners));
+        let delegate_lamports = banks_client.get_balance(delegate.pubkey()).await.unwrap();
+        let transaction = Transaction::new_signed_with_payer(
+            &[multisig_withdraw(&keys)],
+            Some(&payer.pubkey()),
+            &[&payer, &members[0], &members[1]],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+        assert_eq!(
+            banks_client.get_balance(delegate.pubkey()).await.unwrap(),
+            delegate_lamports + 10
+        );
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 15);
+        assert_eq!(data.delegate, Pubkey::default());
+    }
+
+    #[tokio::test]
+    async fn test_multisig_authority() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let (ledger, vault) = add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
+        let (destination, _) = add_ledger(&mut program_test, program_id, Pubkey::new_unique(), 0);
+        let members = [Keypair::new(), Keypair::new(), Keypair::new()];
+        let keys = members
+            .iter()
+            .map(|member| member.pubkey())
+            .collect::<Vec<_>>();
+        let outsider = Keypair::new();
+        let multisig = Keypair::new();
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let custom_error = |error: BankError| {
+            TransactionError::InstructionError(0, InstructionError::Custom(error.code()))
+        };
+
+        // The threshold must be reachable by distinct members
+        for (signers, threshold) in [
+            (keys.clone(), 0),
+            (keys.clone(), 4),
+            (vec![keys[0], keys[0], keys[1]], 2),
+        ] {
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction::create_multisig(
+                    &program_id,
+                    &multisig.pubkey(),
+                    &payer.pubkey(),
+                    &signers,
+                    threshold,
+                )],
+                Some(&payer.pubkey()),
+                &[&payer, &multisig],
+                recent_blockhash,
+            );
+            let err = banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap();
+            assert_eq!(err, custom_error(BankError::InvalidMultisig));
+        }
+
+        // Create a 2-of-3 multisig and hand the ledger over to it
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::create_multisig(
+                    &program_id,
+                    &multisig.pubkey(),
+                    &payer.pubkey(),
+                    &keys,
+                    2,
+                ),
+                instruction::set_authority(
+                    &program_id,
+                    &ledger,
+                    &authority.pubkey(),
+                    &multisig.pubkey(),
+                    0,
+                ),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &multisig, &authority],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+        let account = banks_client
+            .get_account(multisig.pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.owner, program_id);
+        assert_eq!(
+            Multisig::load(&account.data),
+            Ok(Multisig {
+                threshold: 2,
+                signers: keys.clone(),
+            })
+        );
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        assert_eq!(
+            Data::load(&account.data).unwrap().authority,
+            multisig.pubkey()
+        );
+
+        // The former authority has no say anymore
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::withdraw(
+                &program_id,
+                &ledger,
+                &authority.pubkey(),
+                40,
+                1,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(BankError::WrongAuthority));
+
+        // One member, the same member twice, or a member and an outsider are not enough
+        let withdraw = |signers: &[Pubkey]| {
+            instruction::multisig_withdraw(
+                &program_id,
+                &ledger,
+                &multisig.pubkey(),
+                &authority.pubkey(),
+                signers,
+                40,
+                1,
+            )
+        };
+        for (signers, keypairs) in [
+            (vec![keys[0]], vec![&payer, &members[0]]),
+            (vec![keys[0], keys[0]], vec![&payer, &members[0]]),
+            (
+                vec![keys[0], outsider.pubkey()],
+                vec![&payer, &members[0], &outsider],
+            ),
+        ] {
+            let transaction = Transaction::new_signed_with_payer(
+                &[withdraw(&signers)],
+                Some(&payer.pubkey()),
+                &keypairs,
+                recent_blockhash,
+            );
+            let err = banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap();
+            assert_eq!(err, custom_error(BankError::NotEnoughSigners));
+        }
+
+        // Any two members withdraw to the recipient of their choice
+        let transaction = Transaction::new_signed_with_payer(
+            &[withdraw(&[keys[0], keys[2]])],
+            Some(&payer.pubkey()),
+            &[&payer, &members[0], &members[2]],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+        assert_eq!(
+            banks_client.get_balance(authority.pubkey()).await.unwrap(),
+            1_000_000_040
+        );
+        assert_eq!(
+            banks_client.get_balance(vault).await.unwrap(),
+            Rent::default().minimum_balance(0) + 60
+        );
+
+        // Transfers take the same threshold
+        let transfer = |signers: &[Pubkey]| {
+            instruction::with_multisig_signers(
+                instruction::transfer(
+                    &program_id,
+                    &ledger,
+                    &multisig.pubkey(),
+                    &destination,
+                    10,
+                    2,
+                ),
+                &multisig.pubkey(),
+                signers,
+            )
+        };
+        let transaction = Transaction::new_signed_with_payer(
+            &[transfer(&[keys[1]])],
+            Some(&payer.pubkey()),
+            &[&payer, &members[1]],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(BankError::NotEnoughSigners));
+        let transaction = Transaction::new_signed_with_payer(
+            &[transfer(&[keys[1], keys[2]])],
+            Some(&payer.pubkey()),
+            &[&payer, &members[1], &members[2]],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        assert_eq!(Data::load(&account.data).unwrap().balance, 50);
+        let account = banks_client
+            .get_account(destination)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Data::load(&account.data).unwrap().balance, 10);
+
+        // Batches can't tell where the members of one command end, so they refuse multisigs
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::batch(
+                &program_id,
+                &[withdraw(&[keys[0], keys[1]]), withdraw(&[keys[0], keys[1]])],
+                3,
+            )
+            .unwrap()],
+            Some(&payer.pubkey()),
+            &[&payer, &members[0], &members[1]],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(BankError::InvalidInstruction));
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        assert_eq!(Data::load(&account.data).unwrap().balance, 50);
+    }
+
+    #[tokio::test]
+    async fn test_vesting_withdrawals() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let (ledger, _) = add_ledger(&mut program_test, program_id, authority.pubkey(), 10);
+
+        let mut context = program_test.start_with_context().await;
+
+        let funds_locked = |index: u8| {
+            TransactionError::InstructionError(
+                index,
+                InstructionError::Custom(BankError::FundsLocked.code()),
+            )
+        };
+
+        // 100 lamports vest from 1_000 to 2_000, with nothing released before 1_100
+        let vesting = VestingSchedule {
+            start: 1_000,
+            cliff: 1_100,
+            end: 2_000,
+        };
+        warp_to(&mut context, 10, 1_000).await;
+        for invalid in [
+            VestingSchedule {
+                cliff: 900,
+                ..vesting
+            },
+            VestingSchedule {
+                end: 1_050,
+                ..vesting
+            },
+        ] {
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction::deposit_locked(
+                    &program_id,
+                    &ledger,
+                    &context.payer.pubkey(),
+                    &authority.pubkey(),
+                    100,
+                    invalid,
+                    0,
+                )],
+                Some(&context.payer.pubkey()),
+                &[&context.payer, &authority],
+                context.last_blockhash,
+            );
+            let err = context
+                .banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap();
+            assert_eq!(
+                err,
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(BankError::InvalidVestingSchedule.code())
+                )
+            );
+        }
+        // Nobody else can lock the funds of the ledger
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::deposit_locked(
+                &program_id,
+                &ledger,
+                &context.payer.pubkey(),
+                &context.payer.pubkey(),
+                1,
+                VestingSchedule::unlock_at(i64::MAX),
+                0,
+            )],
+            Some(&context.payer.pubkey()),
+            &[&context.payer],
+            context.last_blockhash,
+        );
+        let err = context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(BankError::WrongAuthority.code())
+            )
+        );
+
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::deposit_locked(
+                &program_id,
+                &ledger,
+                &context.payer.pubkey(),
+                &authority.pubkey(),
+                100,
+                vesting,
+                0,
+            )],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+
+        // Only the 10 lamports deposited before are free
+        assert_eq!(
+            withdraw(&mut context, program_id, ledger, &authority, 11, 1).await,
+            Err(funds_locked(0))
+        );
+        withdraw(&mut context, program_id, ledger, &authority, 10, 1)
+            .await
+            .unwrap();
+
+        // Before the cliff nothing is released
+        warp_to(&mut context, 20, 1_099).await;
+        assert_eq!(
+            withdraw(&mut context, program_id, ledger, &authority, 1, 2).await,
+            Err(funds_locked(0))
+        );
+
+        // Half way through, half of it is
+        warp_to(&mut context, 30, 1_500).await;
+        assert_eq!(
+            withdraw(&mut context, program_id, ledger, &authority, 51, 2).await,
+            Err(funds_locked(0))
+        );
+        withdraw(&mut context, program_id, ledger, &authority, 50, 2)
+            .await
+            .unwrap();
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::close(
+                &program_id,
+                &ledger,
+                &authority.pubkey(),
+                &authority.pubkey(),
+                &[],
+                3,
+            )],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        let err = context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, funds_locked(0));
+
+        // A time lock releases everything at once, once the previous lock has vested
+        warp_to(&mut context, 40, 2_000).await;
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::deposit_locked(
+                    &program_id,
+                    &ledger,
+                    &context.payer.pubkey(),
+                    &authority.pubkey(),
+                    20,
+                    VestingSchedule::unlock_at(3_000),
+                    3,
+                ),
+                instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 51, 4),
+            ],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        let err = context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, funds_locked(1));
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::deposit_locked(
+                    &program_id,
+                    &ledger,
+                    &context.payer.pubkey(),
+                    &authority.pubkey(),
+                    20,
+                    VestingSchedule::unlock_at(3_000),
+                    3,
+                ),
+                instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 50, 4),
+            ],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+        let account = context
+            .banks_client
+            .get_account(ledger)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 20);
+        assert_eq!(data.locked(2_999), 20);
+
+        warp_to(&mut context, 50, 3_000).await;
+        withdraw(&mut context, program_id, ledger, &authority, 20, 5)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_limit() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let stranger = add_wallet(&mut program_test);
+        let (ledger, _) = add_ledger(&mut program_test, program_id, authority.pubkey(), 1_000);
+
+        let mut context = program_test.start_with_context().await;
+
+        let custom_error = |index: u8, error: BankError| {
+            TransactionError::InstructionError(index, InstructionError::Custom(error.code()))
+        };
+        let set_limit = |authority: &Keypair, limit: WithdrawalLimit, nonce: u64| {
+            instruction::set_withdrawal_limit(
+                &program_id,
+                &ledger,
+                &authority.pubkey(),
+                limit,
+                nonce,
+            )
+        };
+        let hourly = WithdrawalLimit {
+            amount: 100,
+            window: 3_600,
+        };
+        let start = 10_000;
+        warp_to(&mut context, 10, start).await;
+
+        // Only the authority sets a limit, and it needs a window
+        for (signer, limit, error) in [
+            (&stranger, hourly, BankError::WrongAuthority),
+            (
+                &authority,
+                WithdrawalLimit {
+                    window: 0,
+                    ..hourly
+                },
+                BankError::InvalidWithdrawalLimit,
+            ),
+        ] {
+            let transaction = Transaction::new_signed_with_payer(
+                &[set_limit(signer, limit, 0)],
+                Some(&context.payer.pubkey()),
+                &[&context.payer, signer],
+                context.last_blockhash,
+            );
+            let err = context
+                .banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap();
+            assert_eq!(err, custom_error(0, error));
+        }
+
+        // Tightening the limit applies at once
+        let transaction = Transaction::new_signed_with_payer(
+            &[set_limit(&authority, hourly, 0)],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+        let account = context
+            .banks_client
+            .get_account(ledger)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.withdrawal_limit, hourly);
+        assert_eq!(data.pending_limit_at, 0);
+
+        // Then at most 100 lamports leave per hour, whatever the command
+        withdraw(&mut context, program_id, ledger, &authority, 60, 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            withdraw(&mut context, program_id, ledger, &authority, 41, 2).await,
+            Err(custom_error(0, BankError::WithdrawalLimitExceeded))
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::close(
+                &program_id,
+                &ledger,
+                &authority.pubkey(),
+                &authority.pubkey(),
+                &[],
+                2,
+            )],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        let err = context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(0, BankError::WithdrawalLimitExceeded));
+        withdraw(&mut context, program_id, ledger, &authority, 40, 2)
+            .await
+            .unwrap();
+
+        warp_to(&mut context, 20, start + 3_600).await;
+        withdraw(&mut context, program_id, ledger, &authority, 100, 3)
+            .await
+            .unwrap();
+
+        // The window rolls: half an hour later only half of the limit is available again
+        warp_to(&mut context, 30, start + 5_400).await;
+        assert_eq!(
+            withdraw(&mut context, program_id, ledger, &authority, 51, 4).await,
+            Err(custom_error(0, BankError::WithdrawalLimitExceeded))
+        );
+        withdraw(&mut context, program_id, ledger, &authority, 50, 4)
+            .await
+            .unwrap();
+
+        // Lifting the limit is delayed
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                set_limit(&authority, WithdrawalLimit::default(), 5),
+                instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 1, 6),
+            ],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        let err = context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(1, BankError::WithdrawalLimitExceeded));
+        let transaction = Transaction::new_signed_with_payer(
+            &[set_limit(&authority, WithdrawalLimit::default(), 5)],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, &authority],
+            context.last_blockhash,
+        );
+        context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+        let account = context
+            .banks_client
+            .get_account(ledger)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.withdrawal_limit, hourly);
+        assert_eq!(data.pending_limit_at, start + 5_400 + LIMIT_CHANGE_DELAY);
+
+        warp_to(&mut context, 40, start + 5_400 + LIMIT_CHANGE_DELAY).await;
+        withdraw(&mut context, program_id, ledger, &authority, 200, 6)
+            .await
+            .unwrap();
+        let account = context
+            .banks_client
+            .get_account(ledger)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 550);
+        assert_eq!(data.withdrawal_limit, WithdrawalLimit::default());
+    }
+
+    #[tokio::test]
+    async fn test_config() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let admin = add_wallet(&mut program_test);
+        let new_admin = add_wallet(&mut program_test);
+        let treasury = add_wallet(&mut program_test);
+        let authority = add_wallet(&mut program_test);
+        let (ledger, vault) = add_ledger(&mut program_test, program_id, authority.pubkey(), 1_000);
+        let (config, _) = find_config_address(&program_id);
+        let (fee_vault, _) = find_fee_vault_address(&program_id);
+
+        // The program as deployed with `admin` as its upgrade authority
+        program_test.add_account(
+            bpf_loader_upgradeable::get_program_data_address(&program_id),
+            Account {
+                lamports: 1_000_000_000,
+                data: serialize(&UpgradeableLoaderState::ProgramData {
+                    slot: 0,
+                    upgrade_authority_address: Some(admin.pubkey()),
+                })
+                .unwrap(),
+                owner: bpf_loader_upgradeable::id(),
+                ..Account::default()
+            },
+        );
+
+        let mut context = program_test.start_with_context().await;
+        let banks_client = context.banks_client.clone();
+        let payer = context.payer.insecure_clone();
+        let start = 10_000;
+        warp_to(&mut context, 10, start).await;
+        let recent_blockhash = context.last_blockhash;
+
+        let custom_error = |error: BankError| {
+            TransactionError::InstructionError(0, InstructionError::Custom(error.code()))
+        };
+
+        // Only the upgrade authority creates the config
+        assert_eq!(
+            process(
+                &banks_client,
+                &payer,
+                &[&new_admin],
+                instruction::initialize_config(&program_id, &new_admin.pubkey()),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::NotAdmin))
+        );
+        process(
+            &banks_client,
+            &payer,
+            &[&admin],
+            instruction::initialize_config(&program_id, &admin.pubkey()),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = banks_client.get_account(fee_vault).await.unwrap().unwrap();
+        assert_eq!(account.owner, program_id);
+        assert_eq!(account.lamports, Rent::default().minimum_balance(0));
+        assert_eq!(
+            process(
+                &banks_client,
+                &admin,
+                &[],
+                instruction::initialize_config(&program_id, &admin.pubkey()),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::AlreadyInitialized))
+        );
+
+        // Only the admin changes the settings, within range
+        let update = |admin: &Keypair, paused: bool, fee_bps: u16| {
+            instruction::update_config(
+                &program_id,
+                &admin.pubkey(),
+                paused,
+                fee_bps,
+                &treasury.pubkey(),
+            )
+        };
+        assert_eq!(
+            process(
+                &banks_client,
+                &payer,
+                &[&new_admin],
+                update(&new_admin, false, 250),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::NotAdmin))
+        );
+        assert_eq!(
+            process(
+                &banks_client,
+                &payer,
+                &[&admin],
+                update(&admin, false, MAX_FEE_BPS + 1),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::InvalidConfig))
+        );
+        process(
+            &banks_client,
+            &payer,
+            &[&admin],
+            update(&admin, false, MAX_FEE_BPS),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+
+        // A higher fee waits for the delay, a lower one applies at once
+        let account = banks_client.get_account(config).await.unwrap().unwrap();
+        let data = Config::load(&account.data).unwrap();
+        assert_eq!(data.fee_bps, 0);
+        assert_eq!(data.pending_fee_bps, MAX_FEE_BPS);
+        assert_eq!(data.pending_fee_at, start + FEE_CHANGE_DELAY);
+        process(
+            &banks_client,
+            &payer,
+            &[&authority],
+            instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 100, 0),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        warp_to(&mut context, 20, start + FEE_CHANGE_DELAY).await;
+        let recent_blockhash = context.last_blockhash;
+        process(
+            &banks_client,
+            &payer,
+            &[&admin],
+            update(&admin, false, 250),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = banks_client.get_account(config).await.unwrap().unwrap();
+        let data = Config::load(&account.data).unwrap();
+        assert_eq!(data.fee_bps, 250);
+        assert_eq!(data.pending_fee_at, 0);
+
+        // Withdrawals pay the fee into the fee vault, leaving the config account as it is
+        process(
+            &banks_client,
+            &payer,
+            &[&authority],
+            instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 400, 1),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            banks_client.get_balance(authority.pubkey()).await.unwrap(),
+            1_000_000_490
+        );
+        assert_eq!(
+            banks_client.get_balance(vault).await.unwrap(),
+            Rent::default().minimum_balance(0) + 500
+        );
+        assert_eq!(
+            banks_client.get_balance(fee_vault).await.unwrap(),
+            Rent::default().minimum_balance(0) + 10
+        );
+        assert_eq!(
+            banks_client.get_balance(config).await.unwrap(),
+            Rent::default().minimum_balance(Config::LEN)
+        );
+
+        // The pause switch stops funds in both directions
+        process(
+            &banks_client,
+            &payer,
+            &[&admin],
+            update(&admin, true, 250),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            process(
+                &banks_client,
+                &payer,
+                &[&authority],
+                instruction::deposit(&program_id, &ledger, &authority.pubkey(), 10),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::Paused))
+        );
+        assert_eq!(
+            process(
+                &banks_client,
+                &payer,
+                &[&authority],
+                instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 10, 2),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::Paused))
+        );
+
+        // The new admin takes over, and unpauses
+        process(
+            &banks_client,
+            &payer,
+            &[&admin],
+            instruction::set_admin(&program_id, &admin.pubkey(), &new_admin.pubkey()),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            process(
+                &banks_client,
+                &payer,
+                &[&admin],
+                update(&admin, false, 0),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::NotAdmin))
+        );
+        process(
+            &banks_client,
+            &payer,
+            &[&new_admin],
+            update(&new_admin, false, 0),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        process(
+            &banks_client,
+            &payer,
+            &[&authority],
+            instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 20, 2),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+
+        // Anyone sends the fees to the treasury, and only there
+        assert_eq!(
+            process(
+                &banks_client,
+                &payer,
+                &[&payer],
+                instruction::collect_fees(&program_id, &payer.pubkey()),
+                recent_blockhash
+            )
+            .await,
+            Err(custom_error(BankError::InvalidTreasury))
+        );
+        process(
+            &banks_client,
+            &payer,
+            &[&payer],
+            instruction::collect_fees(&program_id, &treasury.pubkey()),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            banks_client.get_balance(treasury.pubkey()).await.unwrap(),
+            1_000_000_010
+        );
+        assert_eq!(
+            banks_client.get_balance(fee_vault).await.unwrap(),
+            Rent::default().minimum_balance(0)
+        );
+        let account = banks_client.get_account(config).await.unwrap().unwrap();
+        assert_eq!(
+            Config::load(&account.data).unwrap(),
+            Config {
+                admin: new_admin.pubkey(),
+                paused: false,
+                fee_bps: 0,
+                pending_fee_bps: 0,
+                pending_fee_at: 0,
+                treasury: treasury.pubkey(),
+                bump: find_config_address(&program_id).1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let (ledger, vault) = add_ledger(&mut program_test, program_id, authority.pubkey(), 0);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let deposit = instruction::deposit(&program_id, &ledger, &payer.pubkey(), 100);
+        let withdraw = |amount: u64| {
+            instruction::withdraw(&program_id, &ledger, &authority.pubkey(), amount, 0)
+        };
+
+        // A deposit followed by withdrawals, for one sequence number
+        process(
+            &banks_client,
+            &payer,
+            &[&authority],
+            instruction::batch(
+                &program_id,
+                &[deposit.clone(), withdraw(30), withdraw(20)],
+                0,
+            )
+            .unwrap(),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 50);
+        assert_eq!(data.number, 1);
+        assert_eq!(
+            banks_client.get_balance(authority.pubkey()).await.unwrap(),
+            1_000_000_050
+        );
+        assert_eq!(
+            banks_client.get_balance(vault).await.unwrap(),
+            Rent::default().minimum_balance(0) + 50
+        );
+
+        // Invalid commands reject the whole batch before anything runs, and so does a
+        // command failing half way
+        let close = instruction::close(
+            &program_id,
+            &ledger,
+            &authority.pubkey(),
+            &authority.pubkey(),
+            &[],
+            0,
+        );
+        for (instructions, error) in [
+            (
+                vec![withdraw(10), close],
+                InstructionError::Custom(BankError::InvalidInstruction.code()),
+            ),
+            (
+                vec![withdraw(10), withdraw(0)],
+                InstructionError::Custom(BankError::ZeroAmount.code()),
+            ),
+            (
+                vec![withdraw(30), withdraw(30)],
+                InstructionError::Custom(BankError::InsufficientFunds.code()),
+            ),
+        ] {
+            assert_eq!(
+                process(
+                    &banks_client,
+                    &payer,
+                    &[&authority],
+                    instruction::batch(&program_id, &instructions, 1).unwrap(),
+                    recent_blockhash,
+                )
+                .await,
+                Err(TransactionError::InstructionError(0, error))
+            );
+        }
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 50);
+        assert_eq!(data.number, 1);
+
+        // Anyone can deposit, so a batch of deposits leaves the sequence number alone and a
+        // withdrawal signed in advance stays valid
+        process(
+            &banks_client,
+            &payer,
+            &[],
+            instruction::batch(&program_id, &[deposit.clone(), deposit], 7).unwrap(),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        process(
+            &banks_client,
+            &payer,
+            &[&authority],
+            instruction::withdraw(&program_id, &ledger, &authority.pubkey(), 40, 1),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 210);
+        assert_eq!(data.number, 2);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_requires_authority() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = Keypair::new();
+        let (user_account, _) = add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
+
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // The authority is passed but does not sign
+        let mut unsigned =
+            instruction::withdraw(&program_id, &user_account, &authority.pubkey(), 50, 0);
+        unsigned.accounts[2].is_signer = false;
+        let transaction = Transaction::new_signed_with_payer(
+            &[unsigned],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
+        );
+
+        // Someone other than the authority signs
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction::withdraw(
+                &program_id,
+                &user_account,
+                &payer.pubkey(),
+                50,
+                0,
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(BankError::WrongAuthority.code())
+            )
+        );
+
+        // The balance is untouched
+        let account_data = banks_client
+            .get_account(user_account)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account_data.data).unwrap();
+        assert_eq!(data.balance, 100);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_replay_rejected() {
+        let program_id = Pubkey::new_unique();
+        let mut program_test =
+            ProgramTest::new("program_name", program_id, processor!(process_instruction));
+
+        let authority = add_wallet(&mut program_test);
+        let (user_account, _) = add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let withdraw = |nonce: u64, blockhash: Hash| {
+            Transaction::new_signed_with_payer(
+                &[instruction::withdraw(
+                    &program_id,
+                    &user_account,
+                    &authority.pubkey(),
+                    10,
+                    nonce,
+                )],
+                Some(&payer.pubkey()),
+                &[&payer, &authority],
+                blockhash,
+            )
+        };
+        let nonce_error = TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(BankError::InvalidNonce.code()),
+        );
+
+        banks_client
+            .process_transaction(withdraw(0, recent_blockhash))
+            .await
+            .unwrap();
 
-    Ok(())
-}
+        // The same instruction under a fresh blockhash is a different transaction,
+        // but the ledger already consumed nonce 0
+        let blockhash = banks_client
+            .get_new_latest_blockhash(&recent_blockhash)
+            .await
+            .unwrap();
+        let err = banks_client
+            .process_transaction(withdraw(0, blockhash))
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, nonce_error);
 
-// Define the instruction data structure
-#[derive(Deserialize, Serialize, Debug)]
-pub enum Command {
-    Deposit { amount: u64 },
-    Withdraw { amount: u64 },
-    CheckBalance,
-}
+        // Nonces cannot be skipped either
+        let err = banks_client
+            .process_transaction(withdraw(2, blockhash))
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, nonce_error);
 
-// Define the instruction struct
-#[derive(Deserialize, Serialize, Debug)]
-pub struct CommandInstruction {
-    pub command: Command,
-    program_id: Pubkey,
-}
+        banks_client
+            .process_transaction(withdraw(1, blockhash))
+            .await
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bincode::serialize;
-    use solana_program_test::*;
-    use solana_sdk::{
-        account::Account,
-        instruction::{AccountMeta, Instruction},
-        pubkey::Pubkey,
-        signature::Keypair,
-        signer::Signer,
-        transaction::Transaction,
-    };
+        let account_data = banks_client
+            .get_account(user_account)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account_data.data).unwrap();
+        assert_eq!(data.number, 2);
+        assert_eq!(data.balance, 80);
+    }
 
     #[tokio::test]
-    async fn test_deposit() {
+    async fn test_deposit_keeps_nonce() {
         let program_id = Pubkey::new_unique();
         let mut program_test =
             ProgramTest::new("program_name", program_id, processor!(process_instruction));
 
-        // Create an account to hold the data
-        let user_account = Keypair::new();
-        let initial_balance = 1_000_000_000; // Enough lamports to be rent-exempt
-        program_test.add_account(
-            user_account.pubkey(),
-            Account {
-                lamports: initial_balance,
-                data: vec![0; 16], // Allocate space for serialized Data struct (u64 + u64)
-                owner: program_id,
-                ..Account::default()
-            },
-        );
+        let authority = add_wallet(&mut program_test);
+        let (user_account, _) = add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
 
         let (banks_client, payer, recent_blockhash) = program_test.start().await;
 
-        // Deposit 100 SOL
-        let instruction = CommandInstruction {
-            program_id,
-            command: Command::Deposit { amount: 100 },
-        };
-        let instruction_data = serialize(&instruction).unwrap();
-
-        // Create the transaction to call the program
-        let transaction = Transaction::new_signed_with_payer(
-            &[Instruction {
-                program_id,
-                accounts: vec![AccountMeta::new(user_account.pubkey(), false)],
-                data: instruction_data,
-            }],
+        // A withdrawal the authority signed before anyone else deposited
+        let withdraw = Transaction::new_signed_with_payer(
+            &[instruction::withdraw(
+                &program_id,
+                &user_account,
+                &authority.pubkey(),
+                10,
+                0,
+            )],
             Some(&payer.pubkey()),
-            &[&payer],
+            &[&payer, &authority],
             recent_blockhash,
         );
 
-        // Process the transaction with error handling
-        match banks_client.process_transaction(transaction).await {
-            Ok(_) => {
-                // Transaction succeeded, check the balance
-                let account_data = banks_client
-                    .get_account(user_account.pubkey())
-                    .await
-                    .unwrap()
-                    .unwrap();
-                let data: Data = deserialize(&account_data.data).unwrap();
-                assert_eq!(data.balance, 100);
-            }
-            Err(e) => {
-                // Handle the error appropriately
-                eprintln!("Transaction failed: {:?}", e);
-                panic!("Transaction failed: {:?}", e); // You can choose to panic or handle it differently
-            }
-        }
+        // The payer deposits without the signature of the authority
+        process(
+            &banks_client,
+            &payer,
+            &[],
+            instruction::deposit(&program_id, &user_account, &payer.pubkey(), 50),
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+        let account = banks_client
+            .get_account(user_account)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.number, 0);
+        assert_eq!(data.balance, 150);
+
+        // So the signed withdrawal still holds the current nonce
+        banks_client.process_transaction(withdraw).await.unwrap();
+        let account = banks_client
+            .get_account(user_account)
+            .await
+            .unwrap()
+            .unwrap();
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.number, 1);
+        assert_eq!(data.balance, 140);
     }
 
     #[tokio::test]
-    async fn test_withdraw() {
+    async fn test_close() {
         let program_id = Pubkey::new_unique();
         let mut program_test =
             ProgramTest::new("program_name", program_id, processor!(process_instruction));
 
-        // Create an account to hold the data
-        let user_account = Keypair::new();
-        program_test.add_account(
-            user_account.pubkey(),
-            Account {
-                lamports: 1_000_000_000,
-                data: serialize(&Data {
-                    number: 0,
-                    balance: 100,
-                })
-                .unwrap(),
-                owner: program_id,
-                ..Account::default()
-            },
-        );
+        let authority = add_wallet(&mut program_test);
+        let (ledger, vault) = add_ledger(&mut program_test, program_id, authority.pubkey(), 100);
+        let destination = Pubkey::new_unique();
 
         let (banks_client, payer, recent_blockhash) = program_test.start().await;
 
-        // Withdraw 50 SOL
-        let instruction = CommandInstruction {
-            program_id,
-            command: Command::Withdraw { amount: 50 },
+        let close = |destination: Pubkey, mints: &[Pubkey], nonce: u64| {
+            instruction::close(
+                &program_id,
+                &ledger,
+                &authority.pubkey(),
+                &destination,
+                mints,
+                nonce,
+            )
+        };
+        let custom_error = |index: u8, error: BankError| {
+            TransactionError::InstructionError(index, InstructionError::Custom(error.code()))
         };
-        let instruction_data = serialize(&instruction).unwrap();
 
+        // Only the authority can close the account
         let transaction = Transaction::new_signed_with_payer(
-            &[Instruction {
-                program_id,
-                accounts: vec![AccountMeta::new(user_account.pubkey(), false)],
-                data: instruction_data,
-            }],
+            &[instruction::close(
+                &program_id,
+                &ledger,
+                &payer.pubkey(),
+                &destination,
+                &[],
+                0,
+            )],
             Some(&payer.pubkey()),
             &[&payer],
             recent_blockhash,
         );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(0, BankError::WrongAuthority));
 
+        // Tokens must be withdrawn first
+        let (mint, payer_tokens) =
+            create_mint_and_token_account(&banks_client, &payer, &payer.pubkey(), 5).await;
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::token_deposit(
+                    &program_id,
+                    &ledger,
+                    &mint,
+                    &payer.pubkey(),
+                    &payer_tokens,
+                    5,
+                ),
+                close(destination, &[mint], 0),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(1, BankError::TokenBalanceNotZero));
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                instruction::token_deposit(
+                    &program_id,
+                    &ledger,
+                    &mint,
+                    &payer.pubkey(),
+                    &payer_tokens,
+                    5,
+                ),
+                instruction::token_withdraw(
+                    &program_id,
+                    &ledger,
+                    &mint,
+                    &authority.pubkey(),
+                    &payer_tokens,
+                    5,
+                    0,
+                ),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
         banks_client.process_transaction(transaction).await.unwrap();
 
-        // Check the balance
-        let account_data = banks_client
-            .get_account(user_account.pubkey())
+        // The rent can't go to the accounts being closed
+        let transaction = Transaction::new_signed_with_payer(
+            &[close(ledger, &[mint], 1)],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
             .await
-            .unwrap()
+            .unwrap_err()
             .unwrap();
-        let data: Data = deserialize(&account_data.data).unwrap();
-        assert_eq!(data.balance, 50);
+        assert_eq!(err, custom_error(0, BankError::InvalidDestination));
+
+        // The token vaults must be passed to be closed as well
+        let transaction = Transaction::new_signed_with_payer(
+            &[close(destination, &[], 1)],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys)
+        );
+
+        // A closed account can't be used again, even within the same transaction
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                close(destination, &[mint], 1),
+                instruction::deposit(&program_id, &ledger, &payer.pubkey(), 10),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        let err = banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(err, custom_error(1, BankError::InvalidAccountData));
+
+        let ledger_lamports = banks_client.get_balance(ledger).await.unwrap();
+        let vault_lamports = banks_client.get_balance(vault).await.unwrap();
+        let authority_lamports = banks_client.get_balance(authority.pubkey()).await.unwrap();
+        let (token_vault, _) = find_token_vault_address(&program_id, &ledger, &mint);
+        let token_vault_lamports = banks_client.get_balance(token_vault).await.unwrap();
+        let transaction = Transaction::new_signed_with_payer(
+            &[close(destination, &[mint], 1)],
+            Some(&payer.pubkey()),
+            &[&payer, &authority],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        // The balance went to the authority, the rent to the destination, and the
+        // accounts are gone
+        assert_eq!(
+            banks_client.get_balance(authority.pubkey()).await.unwrap(),
+            authority_lamports + 100
+        );
+        assert_eq!(
+            banks_client.get_balance(destination).await.unwrap(),
+            ledger_lamports + vault_lamports + token_vault_lamports - 100
+        );
+        assert_eq!(banks_client.get_account(ledger).await.unwrap(), None);
+        assert_eq!(banks_client.get_account(vault).await.unwrap(), None);
+        assert_eq!(banks_client.get_account(token_vault).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_vested_extreme_schedule() {
+        let vesting = VestingSchedule {
+            start: i64::MIN,
+            cliff: 0,
+            end: i64::MAX,
+        };
+        assert_eq!(vesting.vested(u64::MAX, -1), 0);
+        // Half of the full range has elapsed at the cliff
+        assert_eq!(vesting.vested(1 << 32, 0), 1 << 31);
+        assert_eq!(vesting.vested(u64::MAX, i64::MAX - 1), u64::MAX - 1);
+        assert_eq!(vesting.vested(u64::MAX, i64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_withdrawal_limit_within() {
+        let hourly = WithdrawalLimit {
+            amount: 100,
+            window: 3_600,
+        };
+        let none = WithdrawalLimit::default();
+        assert!(hourly.is_within(&none));
+        assert!(hourly.is_within(&hourly));
+        assert!(!none.is_within(&hourly));
+        // Less at once but faster, or slower but more at once, is not within
+        for (amount, window, within) in
+            [(50, 3_600, true), (50, 1_000, false), (200, 36_000, false)]
+        {
+            assert_eq!(
+                WithdrawalLimit { amount, window }.is_within(&hourly),
+                within
+            );
+        }
+    }
+
+    #[test]
+    fn test_schedule_fee() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.schedule_fee(MAX_FEE_BPS + 1, 0),
+            Err(BankError::InvalidConfig)
+        );
+        assert_eq!(
+            config.schedule_fee(10_000, 0),
+            Err(BankError::InvalidConfig)
+        );
+
+        // A raise waits for the delay, and is charged from then on
+        assert_eq!(config.schedule_fee(100, 5), Ok(5 + FEE_CHANGE_DELAY));
+        assert_eq!(config.fee(10_000, 5 + FEE_CHANGE_DELAY - 1), 0);
+        assert_eq!(config.fee(10_000, 5 + FEE_CHANGE_DELAY), 100);
+
+        // A cut applies at once and drops the pending raise
+        let now = 5 + FEE_CHANGE_DELAY;
+        assert_eq!(config.schedule_fee(50, now), Ok(now));
+        assert_eq!(config.fee(10_000, now), 50);
+        assert_eq!(config.schedule_fee(80, now), Ok(now + FEE_CHANGE_DELAY));
+        assert_eq!(config.schedule_fee(0, now), Ok(now));
+        assert_eq!(config.fee(10_000, now + FEE_CHANGE_DELAY), 0);
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_instruction_encoding() {
+        let commands = [
+            Command::Deposit { amount: 1 },
+            Command::Withdraw { amount: 2 },
+            Command::CheckBalance,
+            Command::Initialize,
+            Command::TokenDeposit { amount: 3 },
+            Command::TokenWithdraw { amount: 4 },
+            Command::Transfer { amount: u64::MAX },
+            Command::Migrate,
+            Command::Close,
+            Command::Approve {
+                delegate: Pubkey::new_unique(),
+                amount: 5,
+            },
+            Command::Revoke,
+            Command::CreateMultisig { threshold: 2 },
+            Command::SetAuthority {
+                authority: Pubkey::new_unique(),
+            },
+            Command::DepositLocked {
+                amount: 6,
+                vesting: VestingSchedule {
+                    start: -7,
+                    cliff: 8,
+                    end: i64::MAX,
+                },
+            },
+            Command::SetWithdrawalLimit {
+                limit: WithdrawalLimit {
+                    amount: 9,
+                    window: 10,
+                },
+            },
+            Command::InitializeConfig,
+            Command::UpdateConfig {
+                paused: true,
+                fee_bps: 11,
+                treasury: Pubkey::new_unique(),
+            },
+            Command::SetAdmin {
+                admin: Pubkey::new_unique(),
+            },
+            Command::CollectFees,
+            Command::CreateEscrow {
+                terms: EscrowTerms {
+                    recipient: Pubkey::new_unique(),
+                    arbiter: Pubkey::new_unique(),
+                    amount: 12,
+                    expiry_slot: 13,
+                    recipient_can_release: true,
+                },
+            },
+            Command::ReleaseEscrow,
+            Command::RefundEscrow,
+            Command::InitializeWithHistory { capacity: 14 },
+        ];
+        let tags = (0..).filter(|&tag| tag != BatchInstruction::TAG);
+        for (tag, command) in tags.zip(commands) {
+            let nonce = if command.needs_nonce() { 9 } else { 0 };
+            let instruction = CommandInstruction::new(command, nonce);
+            let data = instruction.pack();
+            assert_eq!(data[0], tag);
+            assert!(!LegacyCommandInstruction::matches(&data));
+            assert_eq!(CommandInstruction::unpack(&data), Ok(instruction));
+
+            // Truncated or padded data is rejected
+            assert_eq!(
+                CommandInstruction::unpack(&data[..data.len() - 1]),
+                Err(BankError::InvalidInstruction)
+            );
+            let mut padded = data.clone();
+            padded.push(0);
+            assert_eq!(
+                CommandInstruction::unpack(&padded),
+                Err(BankError::InvalidInstruction)
+            );
+        }
+
+        // Tag, little-endian amount, little-endian nonce
+        let data = CommandInstruction::new(Command::Withdraw { amount: 0x0102 }, 3).pack();
+        assert_eq!(data, [1, 2, 1, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            CommandInstruction::new(Command::CheckBalance, 0).pack(),
+            [2]
+        );
+        assert_eq!(
+            CommandInstruction::unpack(&[19]),
+            Err(BankError::InvalidInstruction)
+        );
+        let mut data = CommandInstruction::new(
+            Command::CreateEscrow {
+                terms: EscrowTerms::default(),
+            },
+            0,
+        )
+        .pack();
+        assert_eq!(data.len(), 1 + EscrowTerms::LEN + 8);
+        data[EscrowTerms::LEN] = 2;
+        assert_eq!(
+            CommandInstruction::unpack(&data),
+            Err(BankError::InvalidInstruction)
+        );
+
+        // A batch is its tag and command count, the commands, then one nonce
+        let batch = BatchInstruction::new(
+            vec![
+                Command::Deposit { amount: 1 },
+                Command::Approve {
+                    delegate: Pubkey::new_unique(),
+                    amount: 2,
+                },
+                Command::Withdraw { amount: 3 },
+            ],
+            4,
+        );
+        let data = batch.pack().unwrap();
+        assert_eq!(data[..3], [BatchInstruction::TAG, 3, 0]);
+        assert_eq!(data.len(), 2 + 9 + 41 + 9 + 8);
+        assert_eq!(BatchInstruction::unpack(&data), Ok(batch));
+        assert_eq!(
+            BatchInstruction::unpack(&data[..data.len() - 1]),
+            Err(BankError::InvalidInstruction)
+        );
+        assert_eq!(
+            BatchInstruction::unpack(&[BatchInstruction::TAG, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(BankError::InvalidInstruction)
+        );
+
+        // The command count is a byte, so batches of no or too many commands don't encode
+        let batch = BatchInstruction::new(vec![Command::CheckBalance; 255], 5);
+        let data = batch.pack().unwrap();
+        assert_eq!(data[1], 255);
+        assert_eq!(BatchInstruction::unpack(&data), Ok(batch));
+        assert_eq!(
+            BatchInstruction::new(vec![Command::CheckBalance; 256], 5).pack(),
+            Err(BankError::InvalidInstruction)
+        );
+        assert_eq!(
+            BatchInstruction::new(vec![], 5).pack(),
+            Err(BankError::InvalidInstruction)
+        );
+
+        // The builder only combines instructions carrying a command
+        let program_id = Pubkey::new_unique();
+        let ledger = Pubkey::new_unique();
+        assert_eq!(
+            instruction::batch(&program_id, &[], 0),
+            Err(BankError::InvalidInstruction)
+        );
+        let mut undecodable = instruction::deposit(&program_id, &ledger, &ledger, 1);
+        undecodable.data.push(0);
+        assert_eq!(
+            instruction::batch(&program_id, &[undecodable], 0),
+            Err(BankError::InvalidInstruction)
+        );
+        let deposits = vec![instruction::deposit(&program_id, &ledger, &ledger, 1); 256];
+        assert_eq!(
+            instruction::batch(&program_id, &deposits, 0),
+            Err(BankError::InvalidInstruction)
+        );
+    }
+
+    #[test]
+    fn test_legacy_instruction_decoding() {
+        let program_id = Pubkey::new_unique();
+        let commands = [
+            LegacyCommand::Deposit { amount: 1 },
+            LegacyCommand::Withdraw { amount: u64::MAX },
+            LegacyCommand::CheckBalance,
+        ];
+        for (index, command) in (0u8..).zip(commands) {
+            let legacy = serialize(&LegacyCommandInstruction {
+                command,
+                program_id,
+            })
+            .unwrap();
+            // The baseline layout: variant index as u32, the amount if any, the program id
+            assert_eq!(legacy[..4], [index, 0, 0, 0]);
+            assert_eq!(legacy[legacy.len() - 32..], program_id.to_bytes());
+            assert!(LegacyCommandInstruction::matches(&legacy));
+            assert!(CommandInstruction::unpack(&legacy).is_err());
+            assert_eq!(
+                CommandInstruction::unpack_legacy(&program_id, &legacy),
+                Ok(CommandInstruction::new(command.into(), 0))
+            );
+
+            // The legacy encoding only decodes for the program id it was built for
+            assert_eq!(
+                CommandInstruction::unpack_legacy(&Pubkey::new_unique(), &legacy),
+                Err(BankError::ProgramIdMismatch)
+            );
+            assert_eq!(
+                CommandInstruction::unpack_legacy(&program_id, &legacy[..legacy.len() - 1]),
+                Err(BankError::InvalidInstruction)
+            );
+        }
+
+        // Compact data, valid or not, is never taken for legacy data
+        let withdraw = CommandInstruction::new(Command::Withdraw { amount: 2 }, 3).pack();
+        assert!(!LegacyCommandInstruction::matches(&withdraw));
+        assert!(!LegacyCommandInstruction::matches(&withdraw[..9]));
+        let escrow = CommandInstruction::new(
+            Command::CreateEscrow {
+                terms: EscrowTerms::default(),
+            },
+            0,
+        )
+        .pack();
+        assert!(!LegacyCommandInstruction::matches(&escrow));
     }
 
     #[tokio::test]
-    async fn test_check_balance() {
+    async fn test_legacy_instruction_encoding() {
         let program_id = Pubkey::new_unique();
         let mut program_test =
             ProgramTest::new("program_name", program_id, processor!(process_instruction));
 
-        // Create an account to hold the data
-        let user_account = Keypair::new();
-        program_test.add_account(
-            user_account.pubkey(),
-            Account {
-                lamports: 1_000_000_000,
-                data: serialize(&Data {
-                    number: 0,
-                    balance: 150,
-                })
-                .unwrap(),
-                owner: program_id,
-                ..Account::default()
-            },
-        );
+        let (user_account, _) = add_ledger(&mut program_test, program_id, Pubkey::new_unique(), 0);
 
         let (banks_client, payer, recent_blockhash) = program_test.start().await;
 
-        // Check balance
-        let instruction = CommandInstruction {
-            program_id,
-            command: Command::CheckBalance,
+        // A deposit encoded by an older client
+        let legacy_deposit = |encoded_for: Pubkey| {
+            let mut instruction =
+                instruction::deposit(&program_id, &user_account, &payer.pubkey(), 100);
+            instruction.data = serialize(&LegacyCommandInstruction {
+                command: LegacyCommand::Deposit { amount: 100 },
+                program_id: encoded_for,
+            })
+            .unwrap();
+            instruction
         };
-        let instruction_data = serialize(&instruction).unwrap();
-
         let transaction = Transaction::new_signed_with_payer(
-            &[Instruction {
-                program_id,
-                accounts: vec![AccountMeta::new(user_account.pubkey(), false)],
-                data: instruction_data,
-            }],
+            &[legacy_deposit(program_id)],
             Some(&payer.pubkey()),
             &[&payer],
             recent_blockhash,
         );
-
         banks_client.process_transaction(transaction).await.unwrap();
 
-        // The balance should still be 150
-        let account_data = banks_client
-            .get_account(user_account.pubkey())
+        let account = banks_client
+            .get_account(user_account)
             .await
             .unwrap()
             .unwrap();
-        let data: Data = deserialize(&account_data.data).unwrap();
-        assert_eq!(data.balance, 150);
+        let data = Data::load(&account.data).unwrap();
+        assert_eq!(data.balance, 100);
+        assert_eq!(data.number, 0);
+
+        // The legacy encoding still names the program it was built for
+        let transaction = Transaction::new_signed_with_payer(
+            &[legacy_deposit(Pubkey::new_unique())],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert_eq!(
+            banks_client
+                .process_transaction(transaction)
+                .await
+                .unwrap_err()
+                .unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(BankError::ProgramIdMismatch.code())
+            )
+        );
     }
 
-    #[tokio::test]
-    async fn test_insufficient_funds() {
+    #[derive(Clone, Copy, Debug)]
+    enum LedgerOp {
+        Deposit(u64),
+        Withdraw(u64),
+    }
+
+    fn ledger_op(amount: impl Strategy<Value = u64> + Clone) -> impl Strategy<Value = LedgerOp> {
+        prop_oneof![
+            amount.clone().prop_map(LedgerOp::Deposit),
+            amount.prop_map(LedgerOp::Withdraw),
+        ]
+    }
+
+    /// Apply `ops` to a fresh ledger through `process_instruction`, checking after every
+    /// step that the ledger matches a model and the vault holds exactly the balance.
+    async fn run_ledger_ops(ops: Vec<LedgerOp>) {
         let program_id = Pubkey::new_unique();
         let mut program_test =
             ProgramTest::new("program_name", program_id, processor!(process_instruction));
-
-        // Create an account with a balance of 50
-        let user_account = Keypair::new();
-        program_test.add_account(
-            user_account.pubkey(),
-            Account {
-                lamports: 1_000_000_000,
-                data: serialize(&Data {
-                    number: 0,
-                    balance: 50,
-                })
-                .unwrap(),
-                owner: program_id,
-                ..Account::default()
-            },
-        );
+        let authority = add_wallet(&mut program_test);
+        let (ledger, vault) = add_ledger(&mut program_test, program_id, authority.pubkey(), 0);
 
         let (banks_client, payer, recent_blockhash) = program_test.start().await;
+        let rent_exempt = Rent::default().minimum_balance(0);
+        let mut expected: u64 = 0;
+        let mut nonce: u64 = 0;
 
-        // Attempt to withdraw 100 SOL (should fail)
-        let instruction = CommandInstruction {
-            program_id,
-            command: Command::Withdraw { amount: 100 },
-        };
-        let instruction_data = serialize(&instruction).unwrap();
+        for (step, op) in ops.into_iter().enumerate() {
+            let (instruction, signers, outcome) = match op {
+                LedgerOp::Deposit(amount) => (
+                    instruction::deposit(&program_id, &ledger, &payer.pubkey(), amount),
+                    vec![&payer],
+                    if amount == 0 {
+                        Err(BankError::ZeroAmount)
+                    } else {
+                        expected.checked_add(amount).ok_or(BankError::Overflow)
+                    },
+                ),
+                LedgerOp::Withdraw(amount) => (
+                    instruction::withdraw(&program_id, &ledger, &authority.pubkey(), amount, nonce),
+                    vec![&payer, &authority],
+                    if amount == 0 {
+                        Err(BankError::ZeroAmount)
+                    } else {
+                        expected
+                            .checked_sub(amount)
+                            .ok_or(BankError::InsufficientFunds)
+                    },
+                ),
+            };
+            let transaction = Transaction::new_signed_with_payer(
+                &[
+                    // Keeps the signature unique when the same command repeats
+                    ComputeBudgetInstruction::set_compute_unit_limit(200_000 + step as u32),
+                    instruction,
+                ],
+                Some(&payer.pubkey()),
+                &signers,
+                recent_blockhash,
+            );
+            let result = banks_client.process_transaction(transaction).await;
+            match outcome {
+                Ok(balance) => {
+                    assert!(result.is_ok(), "{:?} failed: {:?}", op, result);
+                    expected = balance;
+                    // Only the withdrawals signed by the authority consume the nonce
+                    if let LedgerOp::Withdraw(_) = op {
+                        nonce += 1;
+                    }
+                }
+                Err(error) => assert_eq!(
+                    result.unwrap_err().unwrap(),
+                    TransactionError::InstructionError(1, InstructionError::Custom(error.code()))
+                ),
+            }
 
-        let transaction = Transaction::new_signed_with_payer(
-            &[Instruction {
-                program_id,
-                accounts: vec![AccountMeta::new(user_account.pubkey(), false)],
-                data: instruction_data,
-            }],
-            Some(&payer.pubkey()),
-            &[&payer],
-            recent_blockhash,
-        );
+            let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+            let data = Data::load(&account.data).unwrap();
+            assert_eq!(data.balance, expected);
+            assert_eq!(data.number, nonce);
+            assert_eq!(
+                banks_client.get_balance(vault).await.unwrap(),
+                rent_exempt + expected
+            );
+        }
+    }
 
-        let result = banks_client.process_transaction(transaction).await;
-        assert!(result.is_err());
+    proptest! {
+        #[test]
+        fn test_checked_ledger_math(
+            start in any::<u64>(),
+            ops in vec(ledger_op(prop_oneof![any::<u64>(), 0..1_000u64]), 0..32),
+        ) {
+            let mut data = Data {
+                balance: start,
+                ..Data::new(Pubkey::default(), 0)
+            };
+            let mint = Pubkey::new_unique();
+            data.credit_token(&mint, start).unwrap();
+
+            // Model the balance with u128 so that overflows are observable
+            let mut expected = start as u128;
+            for op in ops {
+                let (result, token_result, next) = match op {
+                    LedgerOp::Deposit(amount) => (
+                        data.credit(amount),
+                        data.credit_token(&mint, amount),
+                        expected + amount as u128,
+                    ),
+                    LedgerOp::Withdraw(amount) => (
+                        data.debit(amount),
+                        data.debit_token(&mint, amount),
+                        expected.wrapping_sub(amount as u128),
+                    ),
+                };
+                if next > u64::MAX as u128 {
+                    // Either wrapped below zero or past u64::MAX: rejected, nothing changes
+                    let error = match op {
+                        LedgerOp::Deposit(_) => BankError::Overflow,
+                        LedgerOp::Withdraw(_) => BankError::InsufficientFunds,
+                    };
+                    prop_assert_eq!(result, Err(error));
+                    prop_assert_eq!(token_result, Err(error));
+                } else {
+                    prop_assert_eq!(result, Ok(()));
+                    prop_assert_eq!(token_result, Ok(()));
+                    expected = next;
+                }
+                prop_assert_eq!(data.balance as u128, expected);
+                prop_assert_eq!(data.token_balance(&mint) as u128, expected);
+            }
+        }
+
+        #[test]
+        fn test_vested_within_amount(
+            mut times in [any::<i64>(), any::<i64>(), any::<i64>()],
+            amount in any::<u64>(),
+            now in prop_oneof![any::<i64>(), Just(i64::MIN), Just(i64::MAX)],
+        ) {
+            times.sort();
+            let vesting = VestingSchedule {
+                start: times[0],
+                cliff: times[1],
+                end: times[2],
+            };
+            prop_assert!(vesting.is_valid());
+            let vested = vesting.vested(amount, now);
+            prop_assert!(vested <= amount);
+            prop_assert!(vesting.vested(amount, now.saturating_add(1)) >= vested);
+        }
+    }
+
+    proptest! {
+        // Every case starts a test validator, so keep the number of cases small
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn test_ledger_ops_through_program(ops in vec(ledger_op(0..2_000u64), 1..10)) {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(run_ledger_ops(ops));
+        }
     }
 }
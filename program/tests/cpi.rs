@@ -0,0 +1,265 @@
+//! Calls the program from a companion program through the `cpi` module, with a
+//! program-derived address of the companion as the ledger authority.
+
+use program::{
+    cpi, find_config_address, find_fee_vault_address, find_ledger_address, find_vault_address, Data,
+};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    system_program,
+};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    hash::Hash,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::Keypair,
+    signer::Signer,
+    transaction::{Transaction, TransactionError},
+};
+
+/// Seed of the companion address owning the ledger.
+const AUTHORITY_SEED: &[u8] = b"authority";
+
+const INITIALIZE: u8 = 0;
+const DEPOSIT: u8 = 1;
+const WITHDRAW: u8 = 2;
+/// Fail unless the balance of the ledger is the amount
+const EXPECT_BALANCE: u8 = 3;
+
+/// The companion program: the operation tag, followed by the amount and nonce in
+/// little-endian.
+///
+/// Accounts:
+/// 0. `[]` Bank program
+/// 1. `[writable]` Authority, the companion address derived from `AUTHORITY_SEED`
+/// 2. `[writable]` Ledger account of the authority
+/// 3. `[writable]` Vault account of the ledger
+/// 4. `[]` System program
+/// 5. `[]` Config account
+/// 6. `[writable]` Fee vault
+/// 7. `[writable, signer]` Depositor
+fn process_companion(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let bank = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let ledger = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    let fee_vault = next_account_info(accounts_iter)?;
+    let depositor = next_account_info(accounts_iter)?;
+
+    let (authority_address, bump) = Pubkey::find_program_address(&[AUTHORITY_SEED], program_id);
+    if *authority.key != authority_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    let seeds: &[&[u8]] = &[AUTHORITY_SEED, &[bump]];
+
+    let (&operation, rest) = instruction_data
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    if rest.len() != 16 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (amount, nonce) = rest.split_at(8);
+    let amount = u64::from_le_bytes(amount.try_into().unwrap());
+    let nonce = u64::from_le_bytes(nonce.try_into().unwrap());
+
+    match operation {
+        INITIALIZE => {
+            cpi::initialize_signed(bank, authority, ledger, vault, system_program, &[seeds])
+        }
+        DEPOSIT => cpi::deposit(
+            bank,
+            ledger,
+            vault,
+            depositor,
+            system_program,
+            config,
+            amount,
+        ),
+        WITHDRAW => cpi::withdraw_signed(
+            bank,
+            ledger,
+            vault,
+            authority,
+            config,
+            fee_vault,
+            amount,
+            nonce,
+            &[seeds],
+        ),
+        EXPECT_BALANCE => {
+            if cpi::check_balance(bank, ledger)? != amount {
+                return Err(ProgramError::InvalidArgument);
+            }
+            Ok(())
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+fn companion_instruction(
+    companion_id: Pubkey,
+    accounts: &[AccountMeta],
+    operation: u8,
+    amount: u64,
+    nonce: u64,
+) -> Instruction {
+    let mut data = vec![operation];
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&nonce.to_le_bytes());
+    Instruction::new_with_bytes(companion_id, &data, accounts.to_vec())
+}
+
+async fn process(
+    banks_client: &BanksClient,
+    payer: &Keypair,
+    instruction: Instruction,
+    recent_blockhash: Hash,
+) -> Result<(), TransactionError> {
+    let transaction = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client
+        .process_transaction(transaction)
+        .await
+        .map_err(|err| err.unwrap())
+}
+
+#[tokio::test]
+async fn test_cpi_from_companion_program() {
+    let program_id = Pubkey::new_unique();
+    let companion_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "program_name",
+        program_id,
+        processor!(program::process_instruction),
+    );
+    program_test.add_program("companion", companion_id, processor!(process_companion));
+
+    // The authority pays for the ledger, so it is a funded system account
+    let (authority, _) = Pubkey::find_program_address(&[AUTHORITY_SEED], &companion_id);
+    let authority_lamports = 1_000_000_000;
+    program_test.add_account(
+        authority,
+        Account {
+            lamports: authority_lamports,
+            ..Account::default()
+        },
+    );
+
+    let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (ledger, _) = find_ledger_address(&program_id, &authority);
+    let (vault, _) = find_vault_address(&program_id, &ledger);
+    let accounts = [
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new(authority, false),
+        AccountMeta::new(ledger, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(find_config_address(&program_id).0, false),
+        AccountMeta::new(find_fee_vault_address(&program_id).0, false),
+        AccountMeta::new(payer.pubkey(), true),
+    ];
+
+    process(
+        &banks_client,
+        &payer,
+        companion_instruction(companion_id, &accounts, INITIALIZE, 0, 0),
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    assert_eq!(Data::load(&account.data).unwrap().authority, authority);
+    let rent = Rent::default().minimum_balance(Data::LEN) + Rent::default().minimum_balance(0);
+    assert_eq!(
+        banks_client.get_balance(authority).await.unwrap(),
+        authority_lamports - rent
+    );
+
+    // The depositor signed the transaction, so no seeds are needed
+    process(
+        &banks_client,
+        &payer,
+        companion_instruction(companion_id, &accounts, DEPOSIT, 100, 0),
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // The companion signs for its authority
+    process(
+        &banks_client,
+        &payer,
+        companion_instruction(companion_id, &accounts, WITHDRAW, 40, 0),
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        banks_client.get_balance(authority).await.unwrap(),
+        authority_lamports - rent + 40
+    );
+
+    // The balance is read back from the return data of the program
+    process(
+        &banks_client,
+        &payer,
+        companion_instruction(companion_id, &accounts, EXPECT_BALANCE, 60, 0),
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        process(
+            &banks_client,
+            &payer,
+            companion_instruction(companion_id, &accounts, EXPECT_BALANCE, 61, 0),
+            recent_blockhash
+        )
+        .await,
+        Err(TransactionError::InstructionError(
+            0,
+            InstructionError::InvalidArgument
+        ))
+    );
+
+    let data = Data::load(
+        &banks_client
+            .get_account(ledger)
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert_eq!(data.balance, 60);
+    assert_eq!(data.number, 1);
+
+    // A truncated payload is rejected instead of panicking
+    let mut instruction = companion_instruction(companion_id, &accounts, DEPOSIT, 1, 0);
+    instruction.data.truncate(9);
+    assert_eq!(
+        process(&banks_client, &payer, instruction, recent_blockhash).await,
+        Err(TransactionError::InstructionError(
+            0,
+            InstructionError::InvalidInstructionData
+        ))
+    );
+}
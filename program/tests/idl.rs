@@ -0,0 +1,508 @@
+//! Checks `idl.json`, the Codama description of the program that clients are generated
+//! from, against the encoding of the instructions and accounts, the instruction builders
+//! and the errors of the crate.
+//!
+//! Values are generated from the types of the IDL, encoded as it describes, decoded by
+//! the crate and compared through their `Debug` output, so a field added, moved or
+//! resized on either side fails the test.
+
+use program::{
+    error::BankError, instruction, BatchInstruction, CommandInstruction, Config, Data, Escrow,
+    EscrowTerms, History, HistoryEntry, Multisig, Operation, TokenBalance, VestingSchedule,
+    WithdrawalLimit,
+};
+use serde_json::Value;
+use solana_program::{instruction::Instruction, pubkey::Pubkey};
+use std::str::FromStr;
+
+fn idl() -> Value {
+    serde_json::from_str(include_str!("../idl.json")).expect("valid JSON")
+}
+
+fn pascal_case(name: &str) -> String {
+    name[..1].to_uppercase() + &name[1..]
+}
+
+/// Node of `nodes` named `name`.
+fn find<'a>(nodes: &'a Value, name: &str) -> &'a Value {
+    nodes
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|node| node["name"] == name)
+        .unwrap_or_else(|| panic!("no node named {name}"))
+}
+
+fn number_size(format: &str) -> usize {
+    match format {
+        "u8" => 1,
+        "u16" => 2,
+        "u32" => 4,
+        "u64" | "i64" => 8,
+        _ => panic!("unsupported number format {format}"),
+    }
+}
+
+/// Take `len` bytes from the start of `input`.
+fn take<'a>(input: &mut &'a [u8], len: usize) -> &'a [u8] {
+    assert!(input.len() >= len, "data ends early");
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    bytes
+}
+
+/// Decode a little-endian number of `format`, rendered in decimal.
+fn decode_number(format: &str, input: &mut &[u8]) -> String {
+    let mut bytes = [0; 8];
+    bytes[..number_size(format)].copy_from_slice(take(input, number_size(format)));
+    match format {
+        "i64" => i64::from_le_bytes(bytes).to_string(),
+        _ => u64::from_le_bytes(bytes).to_string(),
+    }
+}
+
+/// Decode `input` as `type_node`, rendered like the `Debug` output of the Rust value.
+///
+/// Struct fields with a default value are constant, so they are checked against it
+/// instead of being rendered.
+fn decode(types: &Value, type_node: &Value, input: &mut &[u8]) -> String {
+    match type_node["kind"].as_str().unwrap() {
+        "numberTypeNode" => {
+            assert_eq!(type_node["endian"], "le");
+            decode_number(type_node["format"].as_str().unwrap(), input)
+        }
+        "booleanTypeNode" => match decode(types, &type_node["size"], input).as_str() {
+            "0" => "false".to_string(),
+            "1" => "true".to_string(),
+            byte => panic!("invalid boolean {byte}"),
+        },
+        "publicKeyTypeNode" => Pubkey::try_from(take(input, 32)).unwrap().to_string(),
+        "arrayTypeNode" => {
+            let count = &type_node["count"];
+            assert_eq!(count["kind"], "prefixedCountNode");
+            let len: usize = decode(types, &count["prefix"], input).parse().unwrap();
+            let items: Vec<String> = (0..len)
+                .map(|_| decode(types, &type_node["item"], input))
+                .collect();
+            format!("[{}]", items.join(", "))
+        }
+        "definedTypeLinkNode" => {
+            let name = type_node["name"].as_str().unwrap();
+            let defined = &find(types, name)["type"];
+            // Unit variants of an enum are rendered without the enum name
+            if defined["kind"] == "enumTypeNode" {
+                decode(types, defined, input)
+            } else {
+                format!("{} {}", pascal_case(name), decode(types, defined, input))
+            }
+        }
+        "enumTypeNode" => {
+            let index: usize = decode(types, &type_node["size"], input).parse().unwrap();
+            let variant = &type_node["variants"][index];
+            assert_eq!(variant["kind"], "enumEmptyVariantTypeNode");
+            pascal_case(variant["name"].as_str().unwrap())
+        }
+        "structTypeNode" => {
+            let mut fields = Vec::new();
+            for field in type_node["fields"].as_array().unwrap() {
+                let default = &field["defaultValue"];
+                if default.is_null() {
+                    let name = snake_case(field["name"].as_str().unwrap());
+                    fields.push(format!("{name}: {}", decode(types, &field["type"], input)));
+                } else {
+                    check_constant(types, &field["type"], default, input);
+                }
+            }
+            format!("{{ {} }}", fields.join(", "))
+        }
+        kind => panic!("unsupported type node {kind}"),
+    }
+}
+
+/// Ensure `input` starts with the encoding of the constant `value` of `type_node`.
+fn check_constant(types: &Value, type_node: &Value, value: &Value, input: &mut &[u8]) {
+    match value["kind"].as_str().unwrap() {
+        "bytesValueNode" => {
+            assert_eq!(value["encoding"], "utf8");
+            assert_eq!(type_node["kind"], "fixedSizeTypeNode");
+            let expected = value["data"].as_str().unwrap().as_bytes();
+            assert_eq!(type_node["size"], expected.len());
+            assert_eq!(take(input, expected.len()), expected);
+        }
+        "numberValueNode" => {
+            assert_eq!(decode(types, type_node, input), value["number"].to_string());
+        }
+        kind => panic!("unsupported value node {kind}"),
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for c in name.chars() {
+        if c.is_uppercase() {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// Append a sample value of `type_node` to `data`, each number taking the next value of
+/// `counter`, and return it rendered like its `Debug` output.
+fn encode_sample(types: &Value, type_node: &Value, counter: &mut u8, data: &mut Vec<u8>) -> String {
+    match type_node["kind"].as_str().unwrap() {
+        "numberTypeNode" => {
+            *counter += 1;
+            let format = type_node["format"].as_str().unwrap();
+            let value = match format {
+                // Negative, so a signed field read as unsigned shows
+                "i64" => -i64::from(*counter),
+                _ => i64::from(*counter),
+            };
+            data.extend_from_slice(&value.to_le_bytes()[..number_size(format)]);
+            value.to_string()
+        }
+        "booleanTypeNode" => {
+            data.push(1);
+            "true".to_string()
+        }
+        "publicKeyTypeNode" => {
+            let key = Pubkey::new_unique();
+            data.extend_from_slice(key.as_ref());
+            key.to_string()
+        }
+        "definedTypeLinkNode" => {
+            let name = type_node["name"].as_str().unwrap();
+            let fields: Vec<String> = find(types, name)["type"]["fields"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|field| {
+                    let name = snake_case(field["name"].as_str().unwrap());
+                    let value = encode_sample(types, &field["type"], counter, data);
+                    format!("{name}: {value}")
+                })
+                .collect();
+            format!("{} {{ {} }}", pascal_case(name), fields.join(", "))
+        }
+        kind => panic!("unsupported argument type node {kind}"),
+    }
+}
+
+/// Build the instruction named `name` with distinct keys.
+fn build(program_id: &Pubkey, name: &str) -> Instruction {
+    let key = Pubkey::new_unique;
+    match name {
+        "deposit" => instruction::deposit(program_id, &key(), &key(), 1),
+        "withdraw" => instruction::withdraw(program_id, &key(), &key(), 1, 0),
+        "checkBalance" => instruction::check_balance(program_id, &key()),
+        "initialize" => instruction::initialize(program_id, &key()),
+        "tokenDeposit" => instruction::token_deposit(program_id, &key(), &key(), &key(), &key(), 1),
+        "tokenWithdraw" => {
+            instruction::token_withdraw(program_id, &key(), &key(), &key(), &key(), 1, 0)
+        }
+        "transfer" => instruction::transfer(program_id, &key(), &key(), &key(), 1, 0),
+        "migrate" => instruction::migrate(program_id, &key(), &key()),
+        "close" => instruction::close(program_id, &key(), &key(), &key(), &[], 0),
+        "approve" => instruction::approve(program_id, &key(), &key(), &key(), 1, 0),
+        "revoke" => instruction::revoke(program_id, &key(), &key(), 0),
+        "createMultisig" => instruction::create_multisig(program_id, &key(), &key(), &[], 1),
+        "setAuthority" => instruction::set_authority(program_id, &key(), &key(), &key(), 0),
+        "depositLocked" => instruction::deposit_locked(
+            program_id,
+            &key(),
+            &key(),
+            &key(),
+            1,
+            VestingSchedule::default(),
+            0,
+        ),
+        "setWithdrawalLimit" => instruction::set_withdrawal_limit(
+            program_id,
+            &key(),
+            &key(),
+            WithdrawalLimit::default(),
+            0,
+        ),
+        "initializeConfig" => instruction::initialize_config(program_id, &key()),
+        "updateConfig" => instruction::update_config(program_id, &key(), false, 0, &key()),
+        "setAdmin" => instruction::set_admin(program_id, &key(), &key()),
+        "collectFees" => instruction::collect_fees(program_id, &key()),
+        "createEscrow" => instruction::create_escrow(
+            program_id,
+            &key(),
+            &key(),
+            &key(),
+            &key(),
+            EscrowTerms::default(),
+            0,
+        ),
+        "releaseEscrow" => {
+            instruction::release_escrow(program_id, &key(), &key(), &key(), &key(), &key())
+        }
+        "refundEscrow" => instruction::refund_escrow(program_id, &key(), &key(), &key()),
+        "initializeWithHistory" => instruction::initialize_with_history(program_id, &key(), 1),
+        _ => panic!("no builder for {name}"),
+    }
+}
+
+/// Ensure the accounts of `idl_instruction` are those of `built`, in order, with the
+/// same flags and default addresses.
+fn check_accounts(program: &Value, idl_instruction: &Value, built: &Instruction) {
+    let name = &idl_instruction["name"];
+    let accounts = idl_instruction["accounts"].as_array().unwrap();
+    assert_eq!(accounts.len(), built.accounts.len(), "accounts of {name}");
+    let address_of = |account: &Value| {
+        let index = accounts
+            .iter()
+            .position(|other| other["name"] == account["name"])
+            .unwrap_or_else(|| panic!("no account {} in {name}", account["name"]));
+        built.accounts[index].pubkey
+    };
+    for (account, meta) in accounts.iter().zip(&built.accounts) {
+        let label = format!("{name}.{}", account["name"]);
+        assert_eq!(account["isWritable"], meta.is_writable, "{label}");
+        // Either signs in some uses of the instruction only
+        if account["isSigner"] != "either" {
+            assert_eq!(account["isSigner"], meta.is_signer, "{label}");
+        }
+        let default = &account["defaultValue"];
+        match default["kind"].as_str() {
+            None => {}
+            Some("publicKeyValueNode") => assert_eq!(
+                Pubkey::from_str(default["publicKey"].as_str().unwrap()).unwrap(),
+                meta.pubkey,
+                "{label}"
+            ),
+            Some("pdaValueNode") => {
+                let pda = find(&program["pdas"], default["pda"]["name"].as_str().unwrap());
+                let mut seeds: Vec<Vec<u8>> = Vec::new();
+                for seed in pda["seeds"].as_array().unwrap() {
+                    match seed["kind"].as_str().unwrap() {
+                        "constantPdaSeedNode" => {
+                            assert_eq!(seed["value"]["encoding"], "utf8");
+                            seeds.push(seed["value"]["data"].as_str().unwrap().into());
+                        }
+                        "variablePdaSeedNode" => {
+                            let value =
+                                &find(&default["seeds"], seed["name"].as_str().unwrap())["value"];
+                            let address = match value["kind"].as_str().unwrap() {
+                                "accountValueNode" => address_of(value),
+                                "programIdValueNode" => built.program_id,
+                                kind => panic!("unsupported seed value {kind}"),
+                            };
+                            seeds.push(address.to_bytes().to_vec());
+                        }
+                        kind => panic!("unsupported seed {kind}"),
+                    }
+                }
+                let seeds: Vec<&[u8]> = seeds.iter().map(Vec::as_slice).collect();
+                // PDAs of other programs name their program
+                let owner = match pda["programId"].as_str() {
+                    Some(owner) => Pubkey::from_str(owner).unwrap(),
+                    None => built.program_id,
+                };
+                assert_eq!(
+                    Pubkey::find_program_address(&seeds, &owner).0,
+                    meta.pubkey,
+                    "{label}"
+                );
+            }
+            Some(kind) => panic!("unsupported default value {kind} of {label}"),
+        }
+    }
+}
+
+#[test]
+fn test_idl_instructions() {
+    let idl = idl();
+    let program = &idl["program"];
+    let types = &program["definedTypes"];
+    let program_id = Pubkey::new_unique();
+    let instructions = program["instructions"].as_array().unwrap();
+
+    // Tags follow the order of the instructions, skipping the batch tag
+    let tags = (0..).filter(|&tag| tag != BatchInstruction::TAG);
+    for (tag, idl_instruction) in tags.clone().zip(instructions) {
+        let name = idl_instruction["name"].as_str().unwrap();
+        let arguments = idl_instruction["arguments"].as_array().unwrap();
+        // Instructions are listed in tag order
+        assert_eq!(arguments[0]["name"], "discriminator");
+        assert_eq!(arguments[0]["defaultValue"]["number"], tag, "{name}");
+        assert_eq!(arguments[0]["type"]["format"], "u8");
+
+        let mut data = vec![tag];
+        let mut counter = 0;
+        let mut values = Vec::new();
+        let mut nonce = None;
+        for argument in &arguments[1..] {
+            let value = encode_sample(types, &argument["type"], &mut counter, &mut data);
+            if argument["name"] == "nonce" {
+                nonce = Some(value);
+            } else {
+                values.push(value);
+            }
+        }
+
+        let decoded = CommandInstruction::unpack(&data)
+            .unwrap_or_else(|err| panic!("{name} does not decode: {err}"));
+        assert_eq!(decoded.pack(), data, "{name}");
+        let command = format!("{:?}", decoded.command);
+        assert!(
+            command == pascal_case(name) || command.starts_with(&(pascal_case(name) + " {")),
+            "{name} decodes as {command}"
+        );
+        // Field names may differ from the Rust ones, but every value must be a whole field
+        for value in values {
+            assert!(
+                command.contains(&format!(": {value},"))
+                    || command.contains(&format!(": {value} }}")),
+                "{name}: {command}"
+            );
+        }
+        // The nonce is last, and only for the commands that consume it
+        assert_eq!(decoded.command.needs_nonce(), nonce.is_some(), "{name}");
+        if let Some(nonce) = nonce {
+            assert_eq!(nonce, decoded.nonce.to_string(), "{name}");
+        }
+
+        let built = build(&program_id, name);
+        assert_eq!(built.data[0], tag, "builder of {name}");
+        check_accounts(program, idl_instruction, &built);
+    }
+
+    // Every command is described
+    let next = tags.clone().nth(instructions.len()).unwrap();
+    assert!(CommandInstruction::unpack(&[next]).is_err());
+    assert!(instructions.len() > usize::from(BatchInstruction::TAG));
+    let docs = program["docs"].to_string();
+    assert!(docs.contains(&format!("tag {}", BatchInstruction::TAG)));
+}
+
+#[test]
+fn test_idl_accounts() {
+    let idl = idl();
+    let program = &idl["program"];
+    let types = &program["definedTypes"];
+    let accounts = &program["accounts"];
+    let check = |name: &str, bytes: &[u8], expected: String| {
+        let mut input = bytes;
+        let rendered = decode(types, &find(accounts, name)["data"], &mut input);
+        assert_eq!(format!("{} {rendered}", pascal_case(name)), expected);
+        assert!(input.is_empty(), "{name} has trailing data");
+    };
+
+    let data = Data {
+        number: 1,
+        balance: 2,
+        delegate: Pubkey::new_unique(),
+        delegated_amount: 3,
+        locked_amount: 4,
+        vesting: VestingSchedule {
+            start: -5,
+            cliff: 6,
+            end: 7,
+        },
+        withdrawal_limit: WithdrawalLimit {
+            amount: 8,
+            window: 9,
+        },
+        pending_limit: WithdrawalLimit {
+            amount: 10,
+            window: -11,
+        },
+        pending_limit_at: 12,
+        window_start: 13,
+        window_withdrawn: 14,
+        open_escrows: 22,
+        history: History {
+            capacity: 2,
+            next: 1,
+            entries: vec![
+                HistoryEntry {
+                    kind: Operation::EscrowIn,
+                    amount: 18,
+                    slot: 19,
+                    counterparty: Pubkey::new_unique(),
+                },
+                HistoryEntry {
+                    kind: Operation::TokenDeposit,
+                    amount: 20,
+                    slot: 21,
+                    counterparty: Pubkey::new_unique(),
+                },
+            ],
+        },
+        tokens: vec![
+            TokenBalance {
+                mint: Pubkey::new_unique(),
+                amount: 15,
+            },
+            TokenBalance {
+                mint: Pubkey::new_unique(),
+                amount: 16,
+            },
+        ],
+        ..Data::new(Pubkey::new_unique(), 17)
+    };
+    let mut bytes = vec![0; data.size()];
+    data.store(&mut bytes).unwrap();
+    check("data", &bytes, format!("{data:?}"));
+
+    let config = Config {
+        admin: Pubkey::new_unique(),
+        paused: true,
+        fee_bps: 1,
+        pending_fee_bps: 2,
+        pending_fee_at: -3,
+        treasury: Pubkey::new_unique(),
+        bump: 4,
+    };
+    let mut bytes = vec![0; Config::LEN];
+    config.store(&mut bytes).unwrap();
+    check("config", &bytes, format!("{config:?}"));
+    assert_eq!(find(accounts, "config")["size"], Config::LEN);
+
+    let multisig = Multisig {
+        threshold: 2,
+        signers: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+    };
+    let mut bytes = vec![0; multisig.size()];
+    multisig.store(&mut bytes).unwrap();
+    check("multisig", &bytes, format!("{multisig:?}"));
+
+    let escrow = Escrow {
+        source: Pubkey::new_unique(),
+        payer: Pubkey::new_unique(),
+        terms: EscrowTerms {
+            recipient: Pubkey::new_unique(),
+            arbiter: Pubkey::new_unique(),
+            amount: 1,
+            expiry_slot: 2,
+            recipient_can_release: true,
+        },
+        counted: true,
+    };
+    let mut bytes = vec![0; Escrow::LEN];
+    escrow.store(&mut bytes).unwrap();
+    check("escrow", &bytes, format!("{escrow:?}"));
+    assert_eq!(find(accounts, "escrow")["size"], Escrow::LEN);
+}
+
+#[test]
+fn test_idl_errors() {
+    let idl = idl();
+    let program = &idl["program"];
+    assert_eq!(program["version"], env!("CARGO_PKG_VERSION"));
+
+    let errors = program["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), BankError::ALL.len());
+    for (error, node) in BankError::ALL.iter().zip(errors) {
+        assert_eq!(node["code"], error.code());
+        assert_eq!(
+            pascal_case(node["name"].as_str().unwrap()),
+            format!("{error:?}")
+        );
+        assert_eq!(node["message"], error.to_string());
+    }
+}
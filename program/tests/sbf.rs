@@ -0,0 +1,121 @@
+//! Runs the compiled program instead of the native processor.
+//!
+//! Build the program with `cargo build-sbf` first, then run
+//! `cargo test --features test-sbf`.
+#![cfg(feature = "test-sbf")]
+
+use program::{
+    events::{self, Event},
+    find_ledger_address, find_vault_address, instruction, Data,
+};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account, pubkey::Pubkey, rent::Rent, signature::Keypair, signer::Signer,
+    transaction::Transaction,
+};
+
+#[tokio::test]
+async fn test_sbf_initialize_deposit_withdraw() {
+    let program_id = Pubkey::new_unique();
+    // No processor: the program is loaded from `program.so`
+    let mut program_test = ProgramTest::new("program", program_id, None);
+    program_test.prefer_bpf(true);
+
+    let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (ledger, _) = find_ledger_address(&program_id, &payer.pubkey());
+    let (vault, _) = find_vault_address(&program_id, &ledger);
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            instruction::initialize(&program_id, &payer.pubkey()),
+            instruction::deposit(&program_id, &ledger, &payer.pubkey(), 100),
+            instruction::withdraw(&program_id, &ledger, &payer.pubkey(), 40, 0),
+        ],
+        Some(&payer.pubkey()),
+        &[&payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(ledger).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    let data = Data::load(&account.data).unwrap();
+    assert_eq!(data.balance, 60);
+    assert_eq!(data.number, 1);
+    assert_eq!(data.authority, payer.pubkey());
+    assert_eq!(
+        banks_client.get_balance(vault).await.unwrap(),
+        Rent::default().minimum_balance(0) + 60
+    );
+}
+
+#[tokio::test]
+async fn test_sbf_events() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("program", program_id, None);
+    program_test.prefer_bpf(true);
+    let recipient = Keypair::new();
+    program_test.add_account(
+        recipient.pubkey(),
+        Account {
+            lamports: 1_000_000_000,
+            ..Account::default()
+        },
+    );
+
+    let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (ledger, _) = find_ledger_address(&program_id, &payer.pubkey());
+    let (destination, _) = find_ledger_address(&program_id, &recipient.pubkey());
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            instruction::initialize(&program_id, &payer.pubkey()),
+            instruction::initialize(&program_id, &recipient.pubkey()),
+            instruction::deposit(&program_id, &ledger, &payer.pubkey(), 100),
+            instruction::withdraw(&program_id, &ledger, &payer.pubkey(), 40, 0),
+            instruction::transfer(&program_id, &ledger, &payer.pubkey(), &destination, 15, 1),
+        ],
+        Some(&payer.pubkey()),
+        &[&payer, &recipient],
+        recent_blockhash,
+    );
+    let result = banks_client
+        .process_transaction_with_metadata(transaction)
+        .await
+        .unwrap();
+    assert!(result.result.is_ok());
+
+    // Every command logged its event, in order
+    let logs = result.metadata.unwrap().log_messages;
+    assert_eq!(
+        events::parse_logs(&program_id, &logs),
+        vec![
+            Event::Initialized {
+                ledger,
+                authority: payer.pubkey(),
+            },
+            Event::Initialized {
+                ledger: destination,
+                authority: recipient.pubkey(),
+            },
+            Event::Deposited {
+                ledger,
+                amount: 100,
+                balance: 100,
+            },
+            Event::Withdrew {
+                ledger,
+                amount: 40,
+                balance: 60,
+            },
+            Event::Transferred {
+                source: ledger,
+                destination,
+                amount: 15,
+            },
+        ]
+    );
+
+    // Records are attributed to the program that logged them
+    assert!(events::parse_logs(&Pubkey::new_unique(), &logs).is_empty());
+}